@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use quic_server::codec::Format;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = quic_server::request::decode(data, Format::Msgpack);
+    let _ = quic_server::request::decode(data, Format::Json);
+});
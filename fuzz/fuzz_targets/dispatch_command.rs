@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use quic_server_fuzz::Request;
+
+fuzz_target!(|request: Request| {
+    quic_server_fuzz::dispatch(&request.encode());
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use quic_server_fuzz::Value;
+
+fuzz_target!(|value: Value| {
+    quic_server_fuzz::dispatch(&value.encode());
+});
@@ -0,0 +1,325 @@
+//! Shared pieces of the fuzz targets: a stub server state and the
+//! structure-aware inputs.
+//!
+//! The stub points at a Docker socket that does not exist, so every command
+//! that gets past decoding fails fast on its first Docker call instead of
+//! touching a real daemon.
+//!
+//! ```text
+//! cargo +nightly fuzz run dispatch_command
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use arbitrary::Arbitrary;
+use bollard::container::*;
+use bollard::system::EventsOptions;
+use broker_proto::{Arguments, CommandType, Protocol};
+use rmp_serde::Serializer;
+use serde::Serialize;
+
+use quic_server::auth::Identity;
+use quic_server::config::Config as ServerConfig;
+use quic_server::request;
+use quic_server::session::Session;
+use quic_server::state::State;
+
+/// Runs `input` through `request::handle_request` as if a client had sent it
+/// on a fresh stream, with an in-memory buffer standing in for the response.
+pub fn dispatch(input: &[u8]) {
+    let mut rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let state = State::new(stub_config()).await.unwrap();
+        let session = Session {
+            identity: Identity::Address(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            flows: None,
+            format: Default::default(),
+            access: Default::default(),
+        };
+
+        let mut recv = input;
+        let mut send = Vec::new();
+        let _ = request::handle_request(&state, &session, &mut recv, &mut send).await;
+    });
+}
+
+fn stub_config() -> ServerConfig {
+    let mut config = ServerConfig::default();
+    config.docker.socket = Some("/nonexistent/docker.sock".into());
+    config.docker.timeout = Some(1);
+    // Rate limiting would turn most of a fuzzing run into identical rejections.
+    config.limits.rate.rate = 0.0;
+
+    config
+}
+
+/// An arbitrary msgpack value, including encodings that lie about their size.
+#[derive(Arbitrary, Debug)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Ext(i8, Vec<u8>),
+    /// A str32 header declaring `len` bytes, followed by only `data`.
+    LongStr { len: u32, data: Vec<u8> },
+    /// An array32 header declaring `len` elements, followed by only `items`.
+    LongArray { len: u32, items: Vec<Value> },
+    /// `depth` single element arrays wrapped around `inner`.
+    Nested { depth: u16, inner: Box<Value> },
+}
+
+impl Value {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write(&mut buf);
+        buf
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        use rmp::encode::*;
+
+        // Writing into a Vec cannot fail.
+        match self {
+            Value::Nil => write_nil(buf).unwrap(),
+            Value::Bool(b) => write_bool(buf, *b).unwrap(),
+            Value::Int(i) => {
+                write_sint(buf, *i).unwrap();
+            }
+            Value::Uint(u) => {
+                write_uint(buf, *u).unwrap();
+            }
+            Value::Float(f) => write_f64(buf, *f).unwrap(),
+            Value::Str(s) => write_str(buf, s).unwrap(),
+            Value::Bin(b) => write_bin(buf, b).unwrap(),
+            Value::Array(items) => {
+                write_array_len(buf, items.len() as u32).unwrap();
+                for item in items {
+                    item.write(buf);
+                }
+            }
+            Value::Map(entries) => {
+                write_map_len(buf, entries.len() as u32).unwrap();
+                for (key, value) in entries {
+                    key.write(buf);
+                    value.write(buf);
+                }
+            }
+            Value::Ext(ty, data) => {
+                write_ext_meta(buf, data.len() as u32, *ty).unwrap();
+                buf.extend_from_slice(data);
+            }
+            Value::LongStr { len, data } => {
+                buf.push(0xdb);
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Value::LongArray { len, items } => {
+                buf.push(0xdd);
+                buf.extend_from_slice(&len.to_be_bytes());
+                for item in items {
+                    item.write(buf);
+                }
+            }
+            Value::Nested { depth, inner } => {
+                buf.extend(std::iter::repeat(0x91).take(*depth as usize));
+                inner.write(buf);
+            }
+        }
+    }
+}
+
+/// A well formed command header, where the argument need not match the
+/// command, followed by whatever the client sends after it.
+#[derive(Arbitrary, Debug)]
+pub struct Request {
+    pub command: Command,
+    pub argument: Option<Argument>,
+    pub upload: Vec<u8>,
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum Command {
+    Hello,
+    List,
+    Change,
+    Container,
+    Stats,
+    Top,
+    Log,
+    Stop,
+    Start,
+    Kill,
+    Restart,
+    Prune,
+    Remove,
+    Update,
+    Create,
+    Build,
+    Pull,
+    Push,
+    System,
+    Events,
+    Tunnel,
+    Datagram,
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum Argument {
+    Hello { protocol: u32 },
+    ContainerChanges { name: String },
+    InspectContainer { name: String, size: Option<bool> },
+    Stats { name: String, stream: Option<bool> },
+    Top { name: String, ps_args: Option<String> },
+    Logs { name: String, follow: bool, tail: String },
+    Stop { name: String, t: Option<i64>, dry_run: bool },
+    Start { name: String },
+    Kill { name: String, signal: Option<String> },
+    Restart { name: String, t: Option<isize> },
+    Prune { dry_run: bool },
+    Remove { name: String, force: bool, dry_run: bool },
+    Update { name: String, memory: Option<i64>, cpu_shares: Option<isize> },
+    Create { name: Option<String>, image: Option<String>, cmd: Option<Vec<String>> },
+    Build { dockerfile: String, t: String },
+    Pull { image: String, tag: Option<String>, profile: Option<String> },
+    Push { name: String, tag: Option<String>, profile: Option<String> },
+    Events { filters: Vec<(String, Vec<String>)> },
+    Tunnel { name: String, port: u16 },
+    Datagram { name: String, port: u16 },
+}
+
+impl Request {
+    pub fn encode(self) -> Vec<u8> {
+        let proto = Protocol::command(self.command.into(), self.argument.map(Into::into));
+
+        let mut buf = Vec::new();
+        proto.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        buf.extend_from_slice(&self.upload);
+        buf
+    }
+}
+
+impl From<Command> for CommandType {
+    fn from(command: Command) -> CommandType {
+        match command {
+            Command::Hello => CommandType::Hello,
+            Command::List => CommandType::List,
+            Command::Change => CommandType::Change,
+            Command::Container => CommandType::Container,
+            Command::Stats => CommandType::Stats,
+            Command::Top => CommandType::Top,
+            Command::Log => CommandType::Log,
+            Command::Stop => CommandType::Stop,
+            Command::Start => CommandType::Start,
+            Command::Kill => CommandType::Kill,
+            Command::Restart => CommandType::Restart,
+            Command::Prune => CommandType::Prune,
+            Command::Remove => CommandType::Remove,
+            Command::Update => CommandType::Update,
+            Command::Create => CommandType::Create,
+            Command::Build => CommandType::Build,
+            Command::Pull => CommandType::Pull,
+            Command::Push => CommandType::Push,
+            Command::System => CommandType::System,
+            Command::Events => CommandType::Events,
+            Command::Tunnel => CommandType::Tunnel,
+            Command::Datagram => CommandType::Datagram,
+        }
+    }
+}
+
+impl From<Argument> for Arguments {
+    fn from(argument: Argument) -> Arguments {
+        match argument {
+            Argument::Hello { protocol } => Arguments::Hello { protocol },
+            Argument::ContainerChanges { name } => Arguments::ContainerChanges { name },
+            Argument::InspectContainer { name, size } => Arguments::InspectContainer {
+                name,
+                options: size.map(|size| InspectContainerOptions { size }),
+            },
+            Argument::Stats { name, stream } => Arguments::Stats {
+                name,
+                options: stream.map(|stream| StatsOptions { stream }),
+            },
+            Argument::Top { name, ps_args } => Arguments::Top {
+                name,
+                options: ps_args.map(|ps_args| TopOptions { ps_args }),
+            },
+            Argument::Logs { name, follow, tail } => Arguments::Logs {
+                name,
+                options: Some(LogsOptions {
+                    follow,
+                    stdout: true,
+                    tail,
+                    ..Default::default()
+                }),
+            },
+            Argument::Stop { name, t, dry_run } => Arguments::Stop {
+                name,
+                options: t.map(|t| StopContainerOptions { t }),
+                dry_run,
+            },
+            Argument::Start { name } => Arguments::Start { name, options: None },
+            Argument::Kill { name, signal } => Arguments::Kill {
+                name,
+                options: signal.map(|signal| KillContainerOptions { signal }),
+            },
+            Argument::Restart { name, t } => Arguments::Restart {
+                name,
+                options: t.map(|t| RestartContainerOptions { t }),
+            },
+            Argument::Prune { dry_run } => Arguments::Prune { options: None, dry_run },
+            Argument::Remove { name, force, dry_run } => Arguments::Remove {
+                name,
+                options: Some(RemoveContainerOptions {
+                    force,
+                    ..Default::default()
+                }),
+                dry_run,
+            },
+            Argument::Update { name, memory, cpu_shares } => Arguments::Update {
+                name,
+                options: UpdateContainerOptions {
+                    memory,
+                    cpu_shares,
+                    ..Default::default()
+                },
+            },
+            Argument::Create { name, image, cmd } => Arguments::Create {
+                config: Config {
+                    image,
+                    cmd,
+                    ..Default::default()
+                },
+                options: name.map(|name| CreateContainerOptions { name }),
+            },
+            Argument::Build { dockerfile, t } => Arguments::Build {
+                options: bollard::image::BuildImageOptions {
+                    dockerfile,
+                    t,
+                    ..Default::default()
+                },
+            },
+            Argument::Pull { image, tag, profile } => Arguments::Pull { image, tag, profile },
+            Argument::Push { name, tag, profile } => Arguments::Push { name, tag, profile },
+            Argument::Events { filters } => Arguments::Events {
+                options: Some(EventsOptions {
+                    filters: filters.into_iter().collect(),
+                    ..Default::default()
+                }),
+            },
+            Argument::Tunnel { name, port } => Arguments::Tunnel { name, port },
+            Argument::Datagram { name, port } => Arguments::Datagram { name, port },
+        }
+    }
+}
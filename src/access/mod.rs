@@ -0,0 +1,70 @@
+//! Which commands a listener serves, for read-only and other restricted
+//! listeners.
+
+use std::{collections::HashSet, sync::Arc};
+
+extern crate anyhow;
+use anyhow::{bail, Result};
+use broker_proto::CommandType;
+
+use crate::capabilities;
+
+/// Commands served in read-only mode, none of them change anything.
+pub const READ_ONLY: &[&str] = &["List", "Container", "Change", "Stats", "Top", "Log"];
+
+/// Commands a listener serves. `Hello` and `Authenticate` are always
+/// served so clients can still discover what they may do.
+#[derive(Clone, Debug)]
+pub enum Access {
+    Full,
+    /// Only these commands, by name as in `[limits.commands]`.
+    Only(Arc<HashSet<String>>),
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Full
+    }
+}
+
+impl Access {
+    /// Access of a listener, `commands` narrowing it further when set.
+    pub fn new(read_only: bool, commands: Option<&[String]>) -> Result<Access> {
+        let known = capabilities::commands()
+            .iter()
+            .map(|cmd| format!("{:?}", cmd))
+            .collect::<HashSet<_>>();
+
+        let mut allowed = match commands {
+            Some(commands) => {
+                for cmd in commands {
+                    if !known.contains(cmd) {
+                        bail!("Unknown command {} in listener commands.", cmd);
+                    }
+                }
+                commands.iter().cloned().collect::<HashSet<_>>()
+            }
+            None if read_only => return Ok(Access::read_only()),
+            None => return Ok(Access::Full),
+        };
+        if read_only {
+            allowed.retain(|cmd| READ_ONLY.contains(&cmd.as_str()));
+        }
+
+        Ok(Access::Only(Arc::new(allowed)))
+    }
+
+    pub fn read_only() -> Access {
+        Access::Only(Arc::new(READ_ONLY.iter().map(|cmd| cmd.to_string()).collect()))
+    }
+
+    pub fn allows(&self, cmd: &CommandType) -> bool {
+        match self {
+            Access::Full => true,
+            Access::Only(allowed) => {
+                matches!(cmd, CommandType::Hello | CommandType::Authenticate)
+                    || allowed.contains(&format!("{:?}", cmd))
+            }
+        }
+    }
+}
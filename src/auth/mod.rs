@@ -0,0 +1,226 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Context, Result};
+use ring::{digest, hmac};
+use serde_derive::Deserialize;
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use crate::config::{AuthConfig, TokenConfig};
+use crate::error::{Error, ErrorCode};
+
+/// Who a request is attributed to, for rate limits and authorization.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// Client known only by the address it connected from.
+    Address(IpAddr),
+    /// On-host client on the Unix socket, by its peer credentials.
+    Local { uid: u32, gid: u32 },
+    /// WebSocket client, by the name of its bearer token.
+    Token(String),
+    /// Client that authenticated with an API key or signed token, by name.
+    Key(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Address(ip) => write!(f, "{}", ip),
+            Identity::Local { uid, gid } => write!(f, "local uid={} gid={}", uid, gid),
+            Identity::Token(name) => write!(f, "token {}", name),
+            Identity::Key(name) => write!(f, "key {}", name),
+        }
+    }
+}
+
+/// Contents of `auth.keys_file`, for example:
+///
+/// ```toml
+/// revoked = ["old-laptop"]
+///
+/// [[keys]]
+/// name = "ci"
+/// sha256 = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyFile {
+    /// API keys by name and hex SHA-256.
+    pub keys: Vec<TokenConfig>,
+    /// Names whose signed tokens are no longer accepted.
+    pub revoked: Vec<String>,
+}
+
+/// API keys and signed tokens clients authenticate with on their first
+/// stream.
+///
+/// A signed token is `name:expiry:signature`, expiry in seconds since the
+/// Unix epoch and signature the hex HMAC-SHA256 of `name:expiry` under
+/// `auth.token_key_file`. The key file is read again whenever it changes, so
+/// keys are revoked by removing them and tokens by listing their name.
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    secret: Option<hmac::Key>,
+    ttl: Duration,
+    loaded: Mutex<(Option<Version>, KeyFile)>,
+}
+
+/// Modification time, length and inode of the key file, which together tell
+/// a changed file apart even when a tool kept its modification time.
+type Version = (SystemTime, u64, u64);
+
+impl KeyStore {
+    pub async fn load(config: &AuthConfig) -> Result<KeyStore> {
+        let secret = match &config.token_key_file {
+            Some(path) => {
+                let secret = fs::read(path).await.context("Failed to read token key.")?;
+                let secret = match std::str::from_utf8(&secret).map(str::trim) {
+                    Ok(text) if text.len() >= 64 && text.len() % 2 == 0 && text.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                        hex::decode(text).context("Token key is not valid hex.")?
+                    }
+                    _ => secret,
+                };
+                if secret.len() < 32 {
+                    bail!("Token key must be at least 32 bytes.");
+                }
+                Some(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+            }
+            None => None,
+        };
+
+        let store = KeyStore {
+            path: config.keys_file.clone(),
+            secret,
+            ttl: Duration::from_secs(config.token_ttl_secs),
+            loaded: Mutex::new((None, KeyFile::default())),
+        };
+        // A broken file fails startup here, later it only keeps the last good one.
+        store.reload().await?;
+
+        Ok(store)
+    }
+
+    /// Whether clients must authenticate before their first request.
+    pub fn enabled(&self) -> bool {
+        self.path.is_some() || self.secret.is_some()
+    }
+
+    /// Identity `credential` belongs to, an API key or a signed token.
+    /// Credentials shaped like a token whose signature does not verify are
+    /// looked up as API keys.
+    ///
+    /// Fails with a `PermissionDenied` error for unknown keys and expired or
+    /// revoked tokens.
+    pub async fn authenticate(&self, credential: &str) -> Result<Identity> {
+        if let Err(e) = self.reload().await {
+            error!("Failed to reload key file, keeping the previous keys: {}", e);
+        }
+
+        let credential = credential.trim();
+        if let (Some(secret), Some((name, expiry, signature))) = (&self.secret, split_token(credential)) {
+            let signed = &credential[..credential.len() - signature.len() - 1];
+            // An API key may look like a token; only a valid signature makes it one.
+            let valid = hex::decode(signature).map_or(false, |signature| {
+                hmac::verify(secret, signed.as_bytes(), &signature).is_ok()
+            });
+            if valid {
+                if expiry <= now() {
+                    return Err(denied("Token expired.").into());
+                }
+                if self.loaded.lock().unwrap().1.revoked.iter().any(|r| r == name) {
+                    return Err(denied("Token revoked.").into());
+                }
+
+                return Ok(Identity::Key(name.to_owned()));
+            }
+        }
+
+        let hash = hex::encode(digest::digest(&digest::SHA256, credential.as_bytes()));
+        self.loaded
+            .lock()
+            .unwrap()
+            .1
+            .keys
+            .iter()
+            .find(|k| k.sha256.eq_ignore_ascii_case(&hash))
+            .map(|k| Identity::Key(k.name.clone()))
+            .ok_or_else(|| denied("Invalid credentials.").into())
+    }
+
+    /// Signs a token for `name`, valid for `auth.token_ttl_secs`.
+    pub fn issue(&self, name: &str) -> Result<String> {
+        let secret = self
+            .secret
+            .as_ref()
+            .ok_or_else(|| anyhow!("Issuing tokens requires auth.token_key_file in the config."))?;
+        if name.is_empty() || name.contains(':') {
+            bail!("Token names must be non-empty and may not contain ':'.");
+        }
+
+        let signed = format!("{}:{}", name, now() + self.ttl.as_secs());
+        let signature = hmac::sign(secret, signed.as_bytes());
+
+        Ok(format!("{}:{}", signed, hex::encode(signature)))
+    }
+
+    async fn reload(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // A missing file holds no keys, deleting it revokes them all.
+        let version = match fs::metadata(path).await {
+            Ok(metadata) => Some((metadata.modified()?, metadata.len(), metadata.ino())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read key file {}.", path.display())),
+        };
+        if self.loaded.lock().unwrap().0 == version {
+            return Ok(());
+        }
+
+        let content = match version {
+            Some(_) => fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read key file {}.", path.display()))?,
+            None => {
+                warn!("Key file {} is missing, no keys are accepted.", path.display());
+                String::new()
+            }
+        };
+        let file: KeyFile =
+            toml::from_str(&content).with_context(|| format!("Failed to parse key file {}.", path.display()))?;
+        info!(keys = file.keys.len(), revoked = file.revoked.len(), "Loaded key file.");
+
+        *self.loaded.lock().unwrap() = (version, file);
+        Ok(())
+    }
+}
+
+/// Name, expiry and signature of what looks like a signed token.
+fn split_token(credential: &str) -> Option<(&str, u64, &str)> {
+    let mut parts = credential.rsplitn(3, ':');
+    let signature = parts.next()?;
+    let expiry = parts.next()?.parse().ok()?;
+    let name = parts.next()?;
+
+    Some((name, expiry, signature))
+}
+
+fn denied(message: &str) -> Error {
+    Error::new(ErrorCode::PermissionDenied, message)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+
+extern crate anyhow;
+use anyhow::{bail, Context, Result};
+use bollard::container::*;
+use broker_proto::{Arguments, Body, CommandType};
+use futures::StreamExt;
+use structopt::{self, StructOpt};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use quic_server::client::{Client, Trust};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "broker", about = "Command line client for the container broker.")]
+struct Opt {
+    #[structopt(long = "server", default_value = "127.0.0.1:8000")]
+    server: SocketAddr,
+
+    /// Name the server certificate is checked against.
+    #[structopt(long = "host", default_value = "localhost")]
+    host: String,
+
+    /// Certificate authority, defaults to the server's self-signed certificate.
+    #[structopt(parse(from_os_str), long = "ca")]
+    ca: Option<PathBuf>,
+
+    /// Trust only the server certificate with this SHA-256 fingerprint.
+    #[structopt(long = "pin", conflicts_with = "ca")]
+    pin: Option<String>,
+
+    /// API key or signed token, for servers that require authentication.
+    #[structopt(long = "credential", env = "BROKER_CREDENTIAL", hide_env_values = true)]
+    credential: Option<String>,
+
+    /// Print responses as JSON instead of tables.
+    #[structopt(long = "json")]
+    json: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// List containers.
+    Ls,
+    /// Show low level information about a container.
+    Inspect {
+        name: String,
+        #[structopt(short = "s", long = "size")]
+        size: bool,
+    },
+    /// Print container logs.
+    Logs {
+        name: String,
+        #[structopt(short = "f", long = "follow")]
+        follow: bool,
+        #[structopt(long = "tail", default_value = "all")]
+        tail: String,
+        #[structopt(short = "t", long = "timestamps")]
+        timestamps: bool,
+    },
+    /// Show resource usage of a container.
+    Stats { name: String },
+    /// Show processes running in a container.
+    Top {
+        name: String,
+        #[structopt(long = "ps-args")]
+        ps_args: Option<String>,
+    },
+    Start { name: String },
+    /// Stop a container, or with --filter every running one matching.
+    Stop {
+        #[structopt(required_unless = "filters")]
+        name: Option<String>,
+        /// Filter as key=value, for example label=env=test.
+        #[structopt(long = "filter", conflicts_with = "name")]
+        filters: Vec<String>,
+        /// Seconds to wait before killing the container.
+        #[structopt(short = "t", long = "time")]
+        time: Option<i64>,
+        /// Only show which containers would be stopped.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    Kill {
+        name: String,
+        #[structopt(short = "s", long = "signal", default_value = "SIGKILL")]
+        signal: String,
+    },
+    Restart {
+        name: String,
+        #[structopt(short = "t", long = "time")]
+        time: Option<isize>,
+    },
+    /// Remove a container.
+    Rm {
+        name: String,
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+        #[structopt(short = "v", long = "volumes")]
+        volumes: bool,
+        /// Only show what would be removed.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Remove stopped containers.
+    Prune {
+        /// Filter as key=value, for example label=env=test.
+        #[structopt(long = "filter")]
+        filters: Vec<String>,
+        /// Only show what would be removed.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Change resource limits of a container.
+    Update {
+        name: String,
+        /// Memory limit in bytes.
+        #[structopt(short = "m", long = "memory")]
+        memory: Option<i64>,
+        #[structopt(long = "memory-swap")]
+        memory_swap: Option<i64>,
+        #[structopt(long = "cpu-shares")]
+        cpu_shares: Option<isize>,
+        #[structopt(long = "cpu-period")]
+        cpu_period: Option<i64>,
+        #[structopt(long = "cpu-quota")]
+        cpu_quota: Option<i64>,
+    },
+    /// Create a container from an image or a JSON container config.
+    Create {
+        #[structopt(long = "name")]
+        name: Option<String>,
+        #[structopt(long = "image", required_unless = "config")]
+        image: Option<String>,
+        #[structopt(short = "e", long = "env")]
+        env: Vec<String>,
+        /// Container config in Docker Engine API JSON format.
+        #[structopt(parse(from_os_str), long = "config", conflicts_with = "image")]
+        config: Option<PathBuf>,
+        cmd: Vec<String>,
+    },
+    /// Forward a local TCP port to a port inside a container, one tunnel
+    /// per accepted connection.
+    Tunnel {
+        #[structopt(long = "local", default_value = "127.0.0.1:8080")]
+        local: SocketAddr,
+        name: String,
+        port: u16,
+    },
+}
+
+fn main() {
+    let mut rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    let opt = Opt::from_args();
+    let code = {
+        if let Err(e) = rt.block_on(run(opt)) {
+            eprintln!("ERROR: {}", e);
+            1
+        } else {
+            0
+        }
+    };
+
+    std::process::exit(code);
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let (cmd_type, argument) = request(&opt.cmd)?;
+
+    let trust = match &opt.pin {
+        Some(pin) => Trust::pinned(pin)?,
+        None => {
+            let ca = match &opt.ca {
+                Some(path) => path.clone(),
+                None => {
+                    let dirs = directories::ProjectDirs::from("org", "quinn", "quinn-examples").unwrap();
+                    dirs.data_local_dir().join("cert.der")
+                }
+            };
+            Trust::Authority(std::fs::read(&ca).with_context(|| format!("Failed to read {}.", ca.display()))?)
+        }
+    };
+
+    let client = Client::connect(&opt.server, &opt.host, trust).await?;
+    if let Some(credential) = &opt.credential {
+        client.authenticate(credential).await?;
+    }
+
+    if let Command::Tunnel { local, name, port } = opt.cmd {
+        return forward(Arc::new(client), local, &name, port).await;
+    }
+
+    let mut responses = client.request(cmd_type, argument).await?;
+    while let Some(body) = responses.next().await? {
+        print_body(&body, opt.json)?;
+    }
+
+    client.close();
+
+    Ok(())
+}
+
+fn request(cmd: &Command) -> Result<(CommandType, Option<Arguments>)> {
+    let req = match cmd.clone() {
+        Command::Ls => (CommandType::List, None),
+        Command::Inspect { name, size } => (
+            CommandType::Container,
+            Some(Arguments::InspectContainer {
+                name,
+                options: Some(InspectContainerOptions { size }),
+            }),
+        ),
+        Command::Logs { name, follow, tail, timestamps } => (
+            CommandType::Log,
+            Some(Arguments::Logs {
+                name,
+                options: Some(LogsOptions {
+                    follow,
+                    stdout: true,
+                    stderr: true,
+                    timestamps,
+                    tail,
+                    ..Default::default()
+                }),
+            }),
+        ),
+        Command::Stats { name } => (
+            CommandType::Stats,
+            Some(Arguments::Stats {
+                name,
+                options: Some(StatsOptions { stream: false }),
+            }),
+        ),
+        Command::Top { name, ps_args } => (
+            CommandType::Top,
+            Some(Arguments::Top {
+                name,
+                options: ps_args.map(|ps_args| TopOptions { ps_args }),
+            }),
+        ),
+        Command::Start { name } => (
+            CommandType::Start,
+            Some(Arguments::Start { name, options: None }),
+        ),
+        Command::Stop { name, filters, time, dry_run } => {
+            let options = time.map(|t| StopContainerOptions { t });
+            let arg = match name {
+                Some(name) => Arguments::Stop { name, options, dry_run },
+                None => Arguments::StopMatching { filters: parse_filters(filters)?, options, dry_run },
+            };
+
+            (CommandType::Stop, Some(arg))
+        }
+        Command::Kill { name, signal } => (
+            CommandType::Kill,
+            Some(Arguments::Kill {
+                name,
+                options: Some(KillContainerOptions { signal }),
+            }),
+        ),
+        Command::Restart { name, time } => (
+            CommandType::Restart,
+            Some(Arguments::Restart {
+                name,
+                options: time.map(|t| RestartContainerOptions { t }),
+            }),
+        ),
+        Command::Rm { name, force, volumes, dry_run } => (
+            CommandType::Remove,
+            Some(Arguments::Remove {
+                name,
+                options: Some(RemoveContainerOptions {
+                    force,
+                    v: volumes,
+                    ..Default::default()
+                }),
+                dry_run,
+            }),
+        ),
+        Command::Prune { filters, dry_run } => (
+            CommandType::Prune,
+            Some(Arguments::Prune {
+                options: Some(PruneContainersOptions { filters: parse_filters(filters)? }),
+                dry_run,
+            }),
+        ),
+        Command::Update { name, memory, memory_swap, cpu_shares, cpu_period, cpu_quota } => (
+            CommandType::Update,
+            Some(Arguments::Update {
+                name,
+                options: UpdateContainerOptions {
+                    memory,
+                    memory_swap,
+                    cpu_shares,
+                    cpu_period,
+                    cpu_quota,
+                    ..Default::default()
+                },
+            }),
+        ),
+        // Sent once for every connection `forward` accepts.
+        Command::Tunnel { name, port, .. } => (CommandType::Tunnel, Some(Arguments::Tunnel { name, port })),
+        Command::Create { name, image, env, config, cmd } => {
+            let config = match config {
+                Some(path) => {
+                    let file = std::fs::read(&path).with_context(|| format!("Failed to read {}.", path.display()))?;
+                    serde_json::from_slice(&file).context("Invalid container config.")?
+                }
+                None => Config {
+                    image,
+                    env: if env.is_empty() { None } else { Some(env) },
+                    cmd: if cmd.is_empty() { None } else { Some(cmd) },
+                    ..Default::default()
+                },
+            };
+
+            (
+                CommandType::Create,
+                Some(Arguments::Create {
+                    config,
+                    options: name.map(|name| CreateContainerOptions { name }),
+                }),
+            )
+        }
+    };
+
+    Ok(req)
+}
+
+/// Accepts connections on `local` until interrupted and splices each into
+/// its own tunnel to `port` of container `name`.
+/// Docker filters from `key=value` arguments.
+fn parse_filters(filters: Vec<String>) -> Result<HashMap<String, Vec<String>>> {
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
+    for filter in filters {
+        let mut parts = filter.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => map.entry(key.into()).or_default().push(value.into()),
+            _ => bail!("Filter {} is not key=value.", filter),
+        }
+    }
+
+    Ok(map)
+}
+
+async fn forward(client: Arc<Client>, local: SocketAddr, name: &str, port: u16) -> Result<()> {
+    let mut listener = TcpListener::bind(&local)
+        .await
+        .with_context(|| format!("Failed to listen on {}.", local))?;
+    eprintln!("Forwarding {} to {}:{}.", local, name, port);
+
+    let mut incoming = listener.incoming();
+    while let Some(tcp) = incoming.next().await {
+        let tcp = tcp?;
+        let client = client.clone();
+        let name = name.to_owned();
+
+        tokio::spawn(async move {
+            if let Err(e) = splice(&client, tcp, &name, port).await {
+                eprintln!("Tunnel failed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn splice(client: &Client, mut tcp: TcpStream, name: &str, port: u16) -> Result<()> {
+    let (mut send, mut recv, rest) = client.tunnel(name, port).await?;
+
+    let (mut tcp_recv, mut tcp_send) = tcp.split();
+
+    let upstream = async {
+        io::copy(&mut tcp_recv, &mut send).await?;
+        send.shutdown().await
+    };
+    let downstream = async {
+        tcp_send.write_all(&rest).await?;
+        io::copy(&mut recv, &mut tcp_send).await
+    };
+
+    futures::try_join!(upstream, downstream)?;
+
+    Ok(())
+}
+
+fn print_body(body: &Body, json: bool) -> Result<()> {
+    match body {
+        Body::None => {}
+        Body::LogOutput(logs) => {
+            for log in logs {
+                print!("{}", log);
+            }
+        }
+        Body::ContainerList(containers) if !json => print_containers(containers),
+        Body::DryRun { containers, space_reclaimed } if !json => {
+            print_containers(containers);
+            println!("Would reclaim {} bytes.", space_reclaimed);
+        }
+        Body::TopResult(top) if !json => {
+            let titles = top.titles.iter().map(String::as_str).collect::<Vec<_>>();
+            print_table(&titles, &top.processes);
+        }
+        body => println!("{}", serde_json::to_string_pretty(body)?),
+    }
+
+    Ok(())
+}
+
+fn print_containers(containers: &[APIContainers]) {
+    let rows = containers
+        .iter()
+        .map(|c| {
+            vec![
+                c.id.chars().take(12).collect(),
+                c.names.iter().map(|n| n.trim_start_matches('/')).collect::<Vec<_>>().join(","),
+                c.image.clone(),
+                c.status.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(&["CONTAINER ID", "NAMES", "IMAGE", "STATUS"], &rows);
+}
+
+fn print_table<S: AsRef<str>>(titles: &[&str], rows: &[Vec<S>]) {
+    let mut widths = titles.iter().map(|t| t.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate().take(widths.len()) {
+            widths[i] = widths[i].max(cell.as_ref().chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let padded = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<width$}", c, width = w))
+            .collect::<Vec<_>>();
+        println!("{}", padded.join("   ").trim_end());
+    };
+
+    line(titles.to_vec());
+    for row in rows {
+        line(row.iter().map(AsRef::as_ref).collect());
+    }
+}
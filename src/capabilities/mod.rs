@@ -0,0 +1,135 @@
+//! Protocol versioning and what a client can discover about this server
+//! through the `Hello` command.
+
+extern crate anyhow;
+use anyhow::{bail, Result};
+
+use bollard::Docker;
+use broker_proto::{Body, CommandType, Limits, Protocol};
+
+use crate::codec::Format;
+use crate::error::{Error, ErrorCode};
+use crate::request::{MAX_BUILD_CONTEXT, MAX_HEADER_SIZE};
+use crate::session::Session;
+use crate::state::State;
+use crate::tenant;
+
+/// Version of the broker framing and command set spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest client protocol version the server still answers.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// ALPN identifier of the broker protocol, tracks `PROTOCOL_VERSION`.
+pub const ALPN_BROKER: &[u8] = b"broker/1";
+
+/// ALPN identifier of the broker protocol with JSON frames.
+pub const ALPN_BROKER_JSON: &[u8] = b"broker-json/1";
+
+/// ALPN identifiers offered on the QUIC endpoint, the versioned broker
+/// protocol first and the generic ones from `common` after it so older
+/// clients keep connecting.
+///
+/// The broker version lives here rather than in `common::ALPN_QUIC_HTTP`,
+/// which other QUIC services share. Clients offer the generic identifiers
+/// too, so a client and server on different protocol versions still
+/// complete the handshake and the server refuses the client's requests
+/// with a message naming both versions, see `check_version`.
+pub fn alpn_protocols() -> Vec<&'static [u8]> {
+    let mut protocols = vec![ALPN_BROKER, ALPN_BROKER_JSON];
+    protocols.extend_from_slice(common::ALPN_QUIC_HTTP);
+    protocols
+}
+
+/// Frame format selected by a negotiated ALPN identifier.
+pub fn format_of(alpn: Option<&[u8]>) -> Format {
+    match alpn {
+        Some(ALPN_BROKER_JSON) => Format::Json,
+        _ => Format::Msgpack,
+    }
+}
+
+/// Fails with a message naming both versions when a client speaks a
+/// protocol version this server cannot serve. Checked on the header of
+/// every request and on `Hello`.
+pub fn check_version(version: u32) -> Result<()> {
+    if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+        bail!(Error::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Unsupported protocol version {}, server supports {} to {}.",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Commands `request::handle_request` dispatches.
+pub fn commands() -> Vec<CommandType> {
+    vec![
+        CommandType::Hello,
+        CommandType::Authenticate,
+        CommandType::List,
+        CommandType::Change,
+        CommandType::Container,
+        CommandType::Stats,
+        CommandType::Top,
+        CommandType::Log,
+        CommandType::Stop,
+        CommandType::Start,
+        CommandType::Kill,
+        CommandType::Restart,
+        CommandType::Prune,
+        CommandType::Remove,
+        CommandType::Update,
+        CommandType::Create,
+        CommandType::Build,
+        CommandType::Pull,
+        CommandType::Push,
+        CommandType::System,
+        CommandType::Events,
+        CommandType::Tunnel,
+        CommandType::Datagram,
+    ]
+}
+
+/// Answers a `Hello` from a client speaking protocol `version`, listing the
+/// commands its listener serves to that client.
+pub async fn hello(state: &State, session: &Session, docker: &Docker, version: u32) -> Result<Protocol> {
+    check_version(version)?;
+
+    // Clients of a tenant are not offered the host-wide commands.
+    let tenant = matches!(state.tenants.of(&session.identity), Ok(Some(_)));
+    let running = docker.ping().await.is_ok();
+    let runtimes = if running { vec!["docker".to_owned()] } else { Vec::new() };
+
+    let limits = &state.config.limits;
+    let body = Body::Hello {
+        server: env!("CARGO_PKG_VERSION").to_owned(),
+        protocol: PROTOCOL_VERSION,
+        runtimes,
+        commands: commands()
+            .into_iter()
+            .filter(|cmd| session.access.allows(cmd) && !(tenant && tenant::host_wide(cmd)))
+            .collect(),
+        limits: Limits {
+            max_header_size: MAX_HEADER_SIZE as u64,
+            max_upload_size: MAX_BUILD_CONTEXT as u64,
+            max_concurrent_operations: limits.max_concurrent_operations as u64,
+            rate: limits.rate.rate,
+            burst: limits.rate.burst,
+        },
+    };
+
+    // Without a daemon there is nothing to describe, Hello still answers.
+    if !running {
+        return Ok(Protocol::ok(body));
+    }
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = body;
+
+    Ok(proto)
+}
@@ -0,0 +1,588 @@
+use std::{collections::HashMap, io::Cursor, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Context, Result};
+use bollard::container::*;
+use bollard::image::BuildImageOptions;
+use bollard::system::EventsOptions;
+use broker_proto::{Arguments, Body, CommandType, Protocol};
+use ring::digest;
+use rmp_serde::Deserializer;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::capabilities::{self, PROTOCOL_VERSION};
+use crate::codec::{self, Accept, Codec, Format};
+use crate::error::{Error, ErrorCode};
+use crate::mux;
+
+/// Smallest request header the client compresses.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Largest response frame the client decompresses.
+const MAX_RESPONSE_FRAME: usize = 256 * 1024 * 1024;
+
+/// Sending half of a request stream, on either transport.
+pub type SendHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Receiving half of a request stream, on either transport.
+pub type RecvHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// How the client decides to trust the server certificate.
+#[derive(Clone)]
+pub enum Trust {
+    /// Certificate authority in DER or PEM format the server chain must lead to.
+    Authority(Vec<u8>),
+    /// SHA-256 fingerprint of the exact server certificate.
+    Pinned([u8; 32]),
+}
+
+impl Trust {
+    /// Parses a hex SHA-256 fingerprint, with or without `:` separators.
+    pub fn pinned(fingerprint: &str) -> Result<Trust> {
+        let mut pin = [0u8; 32];
+        if hex::decode_to_slice(fingerprint.replace(':', ""), &mut pin).is_err() {
+            bail!("Fingerprint must be 32 hex encoded bytes.");
+        }
+
+        Ok(Trust::Pinned(pin))
+    }
+}
+
+/// Connection to a broker server.
+///
+/// Every call opens its own stream, so one client can be shared by
+/// concurrent tasks.
+pub struct Client {
+    transport: Transport,
+    /// Codecs offered to the server, most preferred first.
+    codecs: Vec<Codec>,
+    format: Format,
+}
+
+enum Transport {
+    Quic {
+        connection: quinn::Connection,
+        // Dropping the endpoint would stop driving the connection.
+        _endpoint: quinn::Endpoint,
+    },
+    /// Streams multiplexed over TLS or a Unix socket.
+    Mux(mux::Connection),
+}
+
+impl Client {
+    pub async fn connect(server: &SocketAddr, host: &str, trust: Trust) -> Result<Client> {
+        let mut client_config = quinn::ClientConfigBuilder::default();
+        client_config.protocols(&capabilities::alpn_protocols());
+
+        let pin = match trust {
+            Trust::Authority(ca) => {
+                for cert in authority(&ca)? {
+                    client_config.add_certificate_authority(quinn::Certificate::from_der(&cert.0)?)?;
+                }
+                None
+            }
+            Trust::Pinned(pin) => Some(pin),
+        };
+
+        let mut client_config = client_config.build();
+        if let Some(pin) = pin {
+            Arc::make_mut(&mut client_config.crypto)
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedCertificate(pin)));
+        }
+
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.default_client_config(client_config);
+        let bind = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let (endpoint, _) = endpoint.bind(&bind.parse().unwrap())?;
+
+        let quinn::NewConnection { connection, .. } = endpoint
+            .connect(server, host)?
+            .await
+            .context("Failed to connect to broker.")?;
+
+        Ok(Client::new(Transport::Quic {
+            connection,
+            _endpoint: endpoint,
+        }))
+    }
+
+    /// Connects over TLS on TCP to a server started with `--listen-tls`.
+    pub async fn connect_tls(server: &SocketAddr, host: &str, trust: Trust) -> Result<Client> {
+        let mut config = rustls::ClientConfig::new();
+        config.set_protocols(
+            &capabilities::alpn_protocols()
+                .iter()
+                .map(|p| p.to_vec())
+                .collect::<Vec<_>>(),
+        );
+
+        match trust {
+            Trust::Authority(ca) => {
+                for cert in authority(&ca)? {
+                    config
+                        .root_store
+                        .add(&cert)
+                        .map_err(|e| anyhow!("Invalid certificate authority: {:?}", e))?;
+                }
+            }
+            Trust::Pinned(pin) => {
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(PinnedCertificate(pin)));
+            }
+        }
+
+        let name = webpki::DNSNameRef::try_from_ascii_str(host)
+            .map_err(|_| anyhow!("Invalid server name {}.", host))?;
+        let tcp = TcpStream::connect(server).await.context("Failed to connect to broker.")?;
+        tcp.set_nodelay(true)?;
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await
+            .context("Failed to connect to broker.")?;
+
+        Ok(Client::new(Transport::Mux(mux::connect(tls))))
+    }
+
+    /// Connects over QUIC, falling back to TLS on `tls` if the QUIC handshake
+    /// does not complete within `timeout`.
+    pub async fn connect_with_fallback(
+        quic: &SocketAddr,
+        tls: &SocketAddr,
+        host: &str,
+        trust: Trust,
+        timeout: Duration,
+    ) -> Result<Client> {
+        match tokio::time::timeout(timeout, Client::connect(quic, host, trust.clone())).await {
+            Ok(Ok(client)) => Ok(client),
+            Ok(Err(e)) => {
+                debug!("QUIC connection failed, trying TLS: {}", e);
+                Client::connect_tls(tls, host, trust).await
+            }
+            Err(_) => {
+                debug!("QUIC handshake timed out, trying TLS.");
+                Client::connect_tls(tls, host, trust).await
+            }
+        }
+    }
+
+    /// Connects to the Unix socket of a server on the same host, started
+    /// with `--listen-unix`.
+    pub async fn connect_unix(path: &Path) -> Result<Client> {
+        let stream = UnixStream::connect(path).await.context("Failed to connect to broker.")?;
+
+        Ok(Client::new(Transport::Mux(mux::connect(stream))))
+    }
+
+    fn new(transport: Transport) -> Client {
+        Client {
+            transport,
+            codecs: Vec::new(),
+            format: Format::Msgpack,
+        }
+    }
+
+    /// Encodes requests, and has the server encode responses, in `format`.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Lets the server compress responses with any of `codecs`. Request
+    /// headers are compressed with the first one.
+    pub fn set_compression(&mut self, codecs: &[Codec]) {
+        self.codecs = codecs.to_vec();
+    }
+
+    /// The underlying QUIC connection, `None` on the other transports.
+    pub fn connection(&self) -> Option<&quinn::Connection> {
+        match &self.transport {
+            Transport::Quic { connection, .. } => Some(connection),
+            Transport::Mux(_) => None,
+        }
+    }
+
+    pub fn close(&self) {
+        match &self.transport {
+            Transport::Quic { connection, .. } => connection.close(0u32.into(), b"done"),
+            Transport::Mux(connection) => connection.close(),
+        }
+    }
+
+    /// Sends a command and returns the stream of response bodies.
+    pub async fn request(&self, cmd_type: CommandType, argument: Option<Arguments>) -> Result<Responses> {
+        self.request_with(cmd_type, argument, &[]).await
+    }
+
+    /// Sends a command and returns the body of its final response.
+    pub async fn call(&self, cmd_type: CommandType, argument: Option<Arguments>) -> Result<Body> {
+        self.request(cmd_type, argument).await?.last().await
+    }
+
+    async fn request_with(&self, cmd_type: CommandType, argument: Option<Arguments>, upload: &[u8]) -> Result<Responses> {
+        let (mut send, recv) = self.open(cmd_type, argument).await?;
+        send.write_all(upload).await?;
+        send.shutdown().await?;
+
+        Ok(Responses::new(recv, self.format))
+    }
+
+    async fn open(&self, cmd_type: CommandType, argument: Option<Arguments>) -> Result<(SendHalf, RecvHalf)> {
+        let (mut send, recv): (SendHalf, RecvHalf) = match &self.transport {
+            Transport::Quic { connection, .. } => {
+                let (send, recv) = connection.open_bi().await?;
+                (Box::new(send), Box::new(recv))
+            }
+            Transport::Mux(connection) => {
+                let (send, recv) = connection.open();
+                (Box::new(send), Box::new(recv))
+            }
+        };
+
+        let mut request = Protocol::command(cmd_type, argument);
+        request.version = Some(PROTOCOL_VERSION);
+        let mut buf = self.format.encode(&request)?;
+
+        if let Some(first) = self.codecs.first() {
+            let codec = if buf.len() >= COMPRESSION_THRESHOLD { Some(*first) } else { None };
+            let payload = match codec {
+                Some(codec) => codec::compress(codec, &buf, 3)?,
+                None => buf,
+            };
+            buf = codec::seal(Accept::new(&self.codecs), codec, &payload);
+        }
+        send.write_all(&buf).await?;
+
+        Ok((send, recv))
+    }
+
+    pub async fn list(&self) -> Result<Body> {
+        self.call(CommandType::List, None).await
+    }
+
+    pub async fn changes(&self, name: &str) -> Result<Body> {
+        let arg = Arguments::ContainerChanges { name: name.into() };
+        self.call(CommandType::Change, Some(arg)).await
+    }
+
+    pub async fn inspect(&self, name: &str, options: Option<InspectContainerOptions>) -> Result<Body> {
+        let arg = Arguments::InspectContainer { name: name.into(), options };
+        self.call(CommandType::Container, Some(arg)).await
+    }
+
+    pub async fn stats(&self, name: &str, options: Option<StatsOptions>) -> Result<Body> {
+        let arg = Arguments::Stats { name: name.into(), options };
+        self.call(CommandType::Stats, Some(arg)).await
+    }
+
+    /// Stats samples as they are taken, for `options.stream` set.
+    pub async fn follow_stats(&self, name: &str, options: StatsOptions) -> Result<Responses> {
+        let arg = Arguments::Stats { name: name.into(), options: Some(options) };
+        self.request(CommandType::Stats, Some(arg)).await
+    }
+
+    pub async fn top(&self, name: &str, options: Option<TopOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Top { name: name.into(), options };
+        self.call(CommandType::Top, Some(arg)).await
+    }
+
+    /// Followed logs yield one body per chunk until the container stops.
+    pub async fn logs(&self, name: &str, options: Option<LogsOptions>) -> Result<Responses> {
+        let arg = Arguments::Logs { name: name.into(), options };
+        self.request(CommandType::Log, Some(arg)).await
+    }
+
+    pub async fn start(&self, name: &str, options: Option<StartContainerOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Start { name: name.into(), options };
+        self.call(CommandType::Start, Some(arg)).await
+    }
+
+    pub async fn stop(&self, name: &str, options: Option<StopContainerOptions>) -> Result<Body> {
+        let arg = Arguments::Stop { name: name.into(), options, dry_run: false };
+        self.call(CommandType::Stop, Some(arg)).await
+    }
+
+    /// Lists `name` if `stop` would stop it, without stopping it.
+    pub async fn stop_dry_run(&self, name: &str) -> Result<Body> {
+        let arg = Arguments::Stop { name: name.into(), options: None, dry_run: true };
+        self.call(CommandType::Stop, Some(arg)).await
+    }
+
+    /// Stops every running container matching `filters` and returns them.
+    pub async fn stop_matching(&self, filters: HashMap<String, Vec<String>>, options: Option<StopContainerOptions>) -> Result<Body> {
+        let arg = Arguments::StopMatching { filters, options, dry_run: false };
+        self.call(CommandType::Stop, Some(arg)).await
+    }
+
+    /// Lists the containers `stop_matching` would stop, without stopping
+    /// any.
+    pub async fn stop_matching_dry_run(&self, filters: HashMap<String, Vec<String>>) -> Result<Body> {
+        let arg = Arguments::StopMatching { filters, options: None, dry_run: true };
+        self.call(CommandType::Stop, Some(arg)).await
+    }
+
+    pub async fn kill(&self, name: &str, options: Option<KillContainerOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Kill { name: name.into(), options };
+        self.call(CommandType::Kill, Some(arg)).await
+    }
+
+    pub async fn restart(&self, name: &str, options: Option<RestartContainerOptions>) -> Result<Body> {
+        let arg = Arguments::Restart { name: name.into(), options };
+        self.call(CommandType::Restart, Some(arg)).await
+    }
+
+    pub async fn prune(&self, options: Option<PruneContainersOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Prune { options, dry_run: false };
+        self.call(CommandType::Prune, Some(arg)).await
+    }
+
+    /// Lists the containers `prune` would remove and the space it would
+    /// reclaim, without removing any.
+    pub async fn prune_dry_run(&self, options: Option<PruneContainersOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Prune { options, dry_run: true };
+        self.call(CommandType::Prune, Some(arg)).await
+    }
+
+    pub async fn remove(&self, name: &str, options: Option<RemoveContainerOptions>) -> Result<Body> {
+        let arg = Arguments::Remove { name: name.into(), options, dry_run: false };
+        self.call(CommandType::Remove, Some(arg)).await
+    }
+
+    /// Lists `name` and the space removing it would reclaim, without
+    /// removing it.
+    pub async fn remove_dry_run(&self, name: &str, options: Option<RemoveContainerOptions>) -> Result<Body> {
+        let arg = Arguments::Remove { name: name.into(), options, dry_run: true };
+        self.call(CommandType::Remove, Some(arg)).await
+    }
+
+    pub async fn update(&self, name: &str, options: UpdateContainerOptions) -> Result<Body> {
+        let arg = Arguments::Update { name: name.into(), options };
+        self.call(CommandType::Update, Some(arg)).await
+    }
+
+    pub async fn create(&self, config: Config<String>, options: Option<CreateContainerOptions<String>>) -> Result<Body> {
+        let arg = Arguments::Create { config, options };
+        self.call(CommandType::Create, Some(arg)).await
+    }
+
+    /// Uploads the tar `context` and yields build output followed by the image ID.
+    pub async fn build(&self, options: BuildImageOptions<String>, context: &[u8]) -> Result<Responses> {
+        let arg = Arguments::Build { options };
+        self.request_with(CommandType::Build, Some(arg), context).await
+    }
+
+    pub async fn pull(&self, image: &str, tag: Option<String>, profile: Option<String>) -> Result<Body> {
+        let arg = Arguments::Pull { image: image.into(), tag, profile };
+        self.call(CommandType::Pull, Some(arg)).await
+    }
+
+    pub async fn push(&self, name: &str, tag: Option<String>, profile: Option<String>) -> Result<Body> {
+        let arg = Arguments::Push { name: name.into(), tag, profile };
+        self.call(CommandType::Push, Some(arg)).await
+    }
+
+    pub async fn system(&self) -> Result<Body> {
+        self.call(CommandType::System, None).await
+    }
+
+    /// Daemon events as they happen, until `options.until` if set.
+    pub async fn events(&self, options: Option<EventsOptions<String>>) -> Result<Responses> {
+        self.request(CommandType::Events, Some(Arguments::Events { options })).await
+    }
+
+    /// Binds the connection to the identity of an API key or signed token.
+    ///
+    /// Servers that require authentication only accept it as the first
+    /// request of a connection, and close the connection if it fails.
+    pub async fn authenticate(&self, credential: &str) -> Result<Body> {
+        let arg = Arguments::Authenticate { credential: credential.into() };
+        self.call(CommandType::Authenticate, Some(arg)).await
+    }
+
+    /// Server version, protocol version, runtimes, commands and limits.
+    pub async fn hello(&self) -> Result<Body> {
+        let arg = Arguments::Hello { protocol: PROTOCOL_VERSION };
+        self.call(CommandType::Hello, Some(arg)).await
+    }
+
+    /// Opens a TCP tunnel to `port` of container `name`.
+    ///
+    /// Returns the stream halves and any tunnelled bytes that arrived
+    /// together with the broker's response.
+    pub async fn tunnel(&self, name: &str, port: u16) -> Result<(SendHalf, RecvHalf, Vec<u8>)> {
+        let arg = Arguments::Tunnel { name: name.into(), port };
+        let (send, recv) = self.open(CommandType::Tunnel, Some(arg)).await?;
+
+        let mut responses = Responses::new(recv, self.format);
+        responses.next().await?.ok_or_else(|| anyhow!("Broker closed the tunnel without a response."))?;
+        let (recv, rest) = responses.into_inner();
+
+        Ok((send, recv, rest))
+    }
+
+    /// Opens a UDP flow to `port` of container `name` and returns its ID.
+    ///
+    /// The flow stays open until the returned stream is shut down or dropped.
+    /// Only QUIC connections carry datagrams.
+    pub async fn datagram(&self, name: &str, port: u16) -> Result<(u32, SendHalf)> {
+        let arg = Arguments::Datagram { name: name.into(), port };
+        let (send, recv) = self.open(CommandType::Datagram, Some(arg)).await?;
+
+        match Responses::new(recv, self.format).next().await? {
+            Some(Body::Flow(id)) => Ok((id, send)),
+            _ => bail!("Broker did not return a flow ID."),
+        }
+    }
+}
+
+/// Response bodies of one request, in the order the server sent them.
+pub struct Responses {
+    recv: RecvHalf,
+    format: Format,
+    buf: Vec<u8>,
+    /// Buffer length to reach before retrying a frame that failed to decode.
+    retry_at: usize,
+    finished: bool,
+}
+
+impl Responses {
+    fn new(recv: RecvHalf, format: Format) -> Responses {
+        Responses {
+            recv,
+            format,
+            buf: Vec::new(),
+            retry_at: 0,
+            finished: false,
+        }
+    }
+
+    /// Next response body, `None` once the server finished the stream.
+    /// Error responses are returned as `Err` holding an `error::Error`.
+    pub async fn next(&mut self) -> Result<Option<Body>> {
+        match self.next_frame().await? {
+            Some(resp) => match resp.error {
+                Some(message) => Err(Error {
+                    // Servers predating error codes only send the message.
+                    code: resp.code.unwrap_or(ErrorCode::Internal),
+                    message,
+                    status: resp.status,
+                    retry_after_ms: resp.retry_after_ms,
+                }
+                .into()),
+                None => Ok(Some(resp.body)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Body of the final response, skipping progress responses.
+    pub async fn last(mut self) -> Result<Body> {
+        let mut last = None;
+        while let Some(body) = self.next().await? {
+            last = Some(body);
+        }
+
+        last.ok_or_else(|| anyhow!("Broker closed the stream without a response."))
+    }
+
+    fn into_inner(self) -> (RecvHalf, Vec<u8>) {
+        (self.recv, self.buf)
+    }
+
+    async fn next_frame(&mut self) -> Result<Option<Protocol>> {
+        let mut chunk = vec![0u8; 64 * 1024];
+
+        loop {
+            if self.buf.first() == Some(&codec::MARKER) {
+                if let Some(len) = codec::envelope_len(&self.buf, MAX_RESPONSE_FRAME)? {
+                    let (_, frame) = codec::open(&self.buf[..len], MAX_RESPONSE_FRAME)?;
+                    self.buf.drain(..len);
+                    // Envelopes wrap frames in whichever format was negotiated.
+                    let resp = match self.format {
+                        Format::Msgpack => rmp_serde::from_slice(&frame).map_err(|e| anyhow!("Invalid response: {}", e))?,
+                        Format::Json => serde_json::from_slice(&frame).map_err(|e| anyhow!("Invalid response: {}", e))?,
+                    };
+                    return Ok(Some(resp));
+                }
+                if self.finished {
+                    bail!("Invalid response: truncated frame.");
+                }
+            } else if self.format == Format::Json {
+                if let Some(i) = self.buf.iter().position(|b| *b == b'\n') {
+                    let resp = serde_json::from_slice(&self.buf[..i]).map_err(|e| anyhow!("Invalid response: {}", e))?;
+                    self.buf.drain(..=i);
+                    return Ok(Some(resp));
+                }
+                if self.finished && !self.buf.is_empty() {
+                    bail!("Invalid response: truncated frame.");
+                }
+            } else if !self.buf.is_empty() && (self.finished || self.buf.len() >= self.retry_at) {
+                let mut cursor = Cursor::new(&self.buf[..]);
+                match Protocol::deserialize(&mut Deserializer::new(&mut cursor)) {
+                    Ok(resp) => {
+                        let len = cursor.position() as usize;
+                        self.buf.drain(..len);
+                        self.retry_at = 0;
+                        return Ok(Some(resp));
+                    }
+                    Err(e) if self.finished => bail!("Invalid response: {}", e),
+                    // Large frames arrive in many chunks, avoid decoding them on every one.
+                    Err(_) if self.buf.len() >= 64 * 1024 => self.retry_at = self.buf.len() * 2,
+                    Err(_) => {}
+                }
+            }
+
+            if self.finished {
+                return Ok(None);
+            }
+
+            match self.recv.read(&mut chunk).await? {
+                0 => self.finished = true,
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+fn authority(ca: &[u8]) -> Result<Vec<rustls::Certificate>> {
+    if ca.starts_with(b"-----BEGIN") {
+        let certs = quinn::CertificateChain::from_pem(ca)?
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            bail!("No certificate in CA file.");
+        }
+        Ok(certs)
+    } else {
+        Ok(vec![rustls::Certificate(ca.to_vec())])
+    }
+}
+
+struct PinnedCertificate([u8; 32]);
+
+impl rustls::ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let leaf = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+
+        if digest::digest(&digest::SHA256, &leaf.0).as_ref() == &self.0[..] {
+            Ok(rustls::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::TLSError::General(
+                "Server certificate does not match the pinned fingerprint.".into(),
+            ))
+        }
+    }
+}
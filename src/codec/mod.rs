@@ -0,0 +1,290 @@
+//! Optional compression of request and response frames.
+//!
+//! A compressed frame is wrapped in an envelope starting with `0xc1`, the
+//! one marker msgpack never uses, so plain and wrapped frames can be mixed
+//! on the same stream:
+//!
+//! ```text
+//! 0xc1 | accept: u8 | codec: u8 | length: u32 BE | payload
+//! ```
+//!
+//! `accept` is the set of codecs the sender can decode, one bit per codec
+//! id, and `codec` is the id the payload is compressed with, 0 for none.
+//! A client announces what it accepts by wrapping its request header. The
+//! server only compresses responses for clients that did, and only frames
+//! of at least `compression.threshold` bytes.
+//!
+//! Independently of compression, frames are msgpack or, for clients that
+//! ask for it, JSON with one object per line.
+
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+extern crate anyhow;
+use anyhow::{bail, Result};
+use broker_proto::Protocol;
+use rmp_serde::Serializer;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::config::CompressionConfig;
+use crate::error::{Error, ErrorCode};
+use crate::metrics::Metrics;
+
+/// First byte of an envelope.
+pub const MARKER: u8 = 0xc1;
+
+/// Bytes in front of the envelope payload.
+pub const ENVELOPE_HEADER: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Codec {
+    fn from_id(id: u8) -> Result<Option<Codec>> {
+        match id {
+            0 => Ok(None),
+            1 => Ok(Some(Codec::Zstd)),
+            2 => Ok(Some(Codec::Lz4)),
+            _ => bail!(invalid(format!("Unknown compression codec {}.", id))),
+        }
+    }
+
+    fn id(codec: Option<Codec>) -> u8 {
+        codec.map_or(0, |c| c as u8)
+    }
+}
+
+/// Encoding of `Protocol` frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One msgpack value per frame.
+    Msgpack,
+    /// One JSON object per line, chosen with the `broker-json/1` ALPN
+    /// identifier or by a request header starting with `{`.
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Msgpack
+    }
+}
+
+impl Format {
+    /// Format of a request header, judged by its first byte. A msgpack
+    /// request is a map or an array, never the fixint `{` would be.
+    pub fn of(header: &[u8]) -> Format {
+        match header.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => Format::Json,
+            _ => Format::Msgpack,
+        }
+    }
+
+    /// Encodes one frame, JSON frames end with a newline.
+    pub fn encode(self, frame: &Protocol) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        match self {
+            Format::Msgpack => frame.serialize(&mut Serializer::new(&mut output))?,
+            Format::Json => {
+                serde_json::to_writer(&mut output, frame)?;
+                output.push(b'\n');
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+/// Codecs a peer can decode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Accept(u8);
+
+impl Accept {
+    pub fn new(codecs: &[Codec]) -> Accept {
+        Accept(codecs.iter().fold(0, |bits, c| bits | (1 << *c as u8)))
+    }
+
+    pub fn contains(self, codec: Codec) -> bool {
+        self.0 & (1 << codec as u8) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+pub fn compress(codec: Codec, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::Zstd => zstd::stream::encode_all(data, level),
+        Codec::Lz4 => lz4::block::compress(data, None, true),
+    }
+}
+
+/// Decompresses `payload`, failing if the content is larger than `limit`.
+pub fn decompress(codec: Codec, payload: &[u8], limit: usize) -> Result<Vec<u8>> {
+    match codec {
+        Codec::Zstd => {
+            let mut content = Vec::new();
+            zstd::stream::read::Decoder::new(payload)
+                .and_then(|d| d.take(limit as u64 + 1).read_to_end(&mut content))
+                .map_err(|e| invalid(format!("Invalid zstd payload: {}", e)))?;
+            if content.len() > limit {
+                bail!(invalid(format!("Decompressed frame larger than {} bytes.", limit)));
+            }
+            Ok(content)
+        }
+        Codec::Lz4 => {
+            // The block starts with the content size as a little endian i32,
+            // check it before lz4 allocates for it.
+            let size = match payload.get(..4) {
+                Some(b) => i32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                None => bail!(invalid("Invalid lz4 payload.")),
+            };
+            if size < 0 || size as usize > limit {
+                bail!(invalid(format!("Decompressed frame larger than {} bytes.", limit)));
+            }
+
+            lz4::block::decompress(payload, None).map_err(|e| invalid(format!("Invalid lz4 payload: {}", e)).into())
+        }
+    }
+}
+
+/// Wraps `payload`, compressed with `codec` or plain, in an envelope.
+pub fn seal(accept: Accept, codec: Option<Codec>, payload: &[u8]) -> Vec<u8> {
+    let mut envelope = Vec::with_capacity(ENVELOPE_HEADER + payload.len());
+    envelope.push(MARKER);
+    envelope.push(accept.0);
+    envelope.push(Codec::id(codec));
+    envelope.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    envelope.extend_from_slice(payload);
+    envelope
+}
+
+/// Length of the envelope at the start of `buf`, or `None` while it is
+/// still incomplete. Fails for payloads longer than `limit`.
+pub fn envelope_len(buf: &[u8], limit: usize) -> Result<Option<usize>> {
+    let header = match buf.get(..ENVELOPE_HEADER) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let len = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    if len > limit {
+        bail!(invalid("Request header too large."));
+    }
+
+    if buf.len() < ENVELOPE_HEADER + len {
+        return Ok(None);
+    }
+
+    Ok(Some(ENVELOPE_HEADER + len))
+}
+
+/// Codecs the sender accepts and the content of a complete envelope, which
+/// may be at most `limit` bytes once decompressed.
+pub fn open(envelope: &[u8], limit: usize) -> Result<(Accept, Vec<u8>)> {
+    if envelope.len() < ENVELOPE_HEADER || envelope[0] != MARKER {
+        bail!(invalid("Invalid frame envelope."));
+    }
+
+    let accept = Accept(envelope[1]);
+    let payload = &envelope[ENVELOPE_HEADER..];
+
+    let content = match Codec::from_id(envelope[2])? {
+        Some(codec) => decompress(codec, payload, limit)?,
+        None if payload.len() <= limit => payload.to_vec(),
+        None => bail!(invalid("Request header too large.")),
+    };
+
+    Ok((accept, content))
+}
+
+fn invalid<S: Into<String>>(message: S) -> Error {
+    Error::new(ErrorCode::InvalidArgument, message)
+}
+
+/// Writes response frames to a stream, compressing them once the client
+/// accepted one of the configured codecs.
+///
+/// Other writes pass through untouched, tunnels keep using it as a plain
+/// stream.
+pub struct Writer<'a, W> {
+    inner: &'a mut W,
+    format: Format,
+    compression: Option<(Codec, &'a CompressionConfig, &'a Metrics)>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> Writer<'a, W> {
+    pub fn new(inner: &'a mut W, format: Format) -> Writer<'a, W> {
+        Writer {
+            inner,
+            format,
+            compression: None,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Answers in `format` from now on.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Picks the first configured codec the client accepts.
+    pub fn negotiate(&mut self, accept: Accept, config: &'a CompressionConfig, metrics: &'a Metrics) {
+        self.compression = config
+            .codecs
+            .iter()
+            .find(|c| accept.contains(**c))
+            .map(|codec| (*codec, config, metrics));
+    }
+
+    pub async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some((codec, config, metrics)) = self.compression {
+            if frame.len() >= config.threshold {
+                let payload = compress(codec, frame, config.level)?;
+
+                // Incompressible frames go out as they are.
+                if payload.len() + ENVELOPE_HEADER < frame.len() {
+                    let envelope = seal(Accept::default(), Some(codec), &payload);
+                    metrics.record_compression(frame.len(), envelope.len());
+                    debug!(
+                        codec = ?codec,
+                        original = frame.len(),
+                        compressed = envelope.len(),
+                        ratio = frame.len() as f64 / envelope.len() as f64,
+                        "Compressed frame."
+                    );
+
+                    return self.inner.write_all(&envelope).await;
+                }
+            }
+        }
+
+        self.inner.write_all(frame).await
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for Writer<'a, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
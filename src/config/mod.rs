@@ -0,0 +1,473 @@
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
+
+use tokio::fs;
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_derive::Deserialize;
+
+use crate::codec::Codec;
+use crate::registry::Profile;
+
+/// Server configuration, read from the TOML file given with `--config`.
+///
+/// Every section is optional, a missing file section keeps its defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub docker: DockerConfig,
+    pub registry: RegistryConfig,
+    pub transport: TransportConfig,
+    pub limits: LimitsConfig,
+    pub compression: CompressionConfig,
+    pub gateway: GatewayConfig,
+    pub local: LocalConfig,
+    pub auth: AuthConfig,
+    /// Listeners in addition to those given on the command line.
+    pub listeners: Vec<ListenerConfig>,
+    /// Namespaces teams sharing the host are confined to.
+    pub tenants: Vec<TenantConfig>,
+    /// Clients that see every tenant's containers.
+    pub operators: OperatorsConfig,
+    pub policy: PolicyConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DockerConfig {
+    /// Unix socket of the Docker daemon, Docker's own defaults when unset.
+    pub socket: Option<PathBuf>,
+    /// Seconds to wait for the daemon before a request fails.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RegistryConfig {
+    /// Registries images may be pulled from or pushed to.
+    pub allow: Vec<String>,
+    /// Credential profiles stored in plain text in this file.
+    pub profiles: Vec<Profile>,
+    /// Credential profiles sealed with `--seal`.
+    pub credentials_file: Option<PathBuf>,
+    /// 32 byte key, raw or hex encoded, used to open `credentials_file`.
+    pub key_file: Option<PathBuf>,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            allow: vec![crate::registry::DEFAULT_REGISTRY.into()],
+            profiles: Vec::new(),
+            credentials_file: None,
+            key_file: None,
+        }
+    }
+}
+
+/// QUIC transport parameters, unset values keep quinn's defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+    /// Close connections idle for this long, 0 disables the timeout.
+    pub idle_timeout_ms: Option<u64>,
+    /// Send keep-alives this often so idle clients behind NAT stay reachable.
+    pub keep_alive_interval_ms: Option<u64>,
+    pub max_concurrent_bidi_streams: Option<u64>,
+    /// Bytes a peer may send on one stream before it is read.
+    pub stream_receive_window: Option<u64>,
+    /// Bytes a peer may send on all streams of a connection before they are read.
+    pub receive_window: Option<u64>,
+    pub datagram_receive_buffer: Option<usize>,
+    pub congestion: CongestionConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CongestionConfig {
+    pub controller: Congestion,
+    /// Congestion window in bytes a connection starts with.
+    pub initial_window: Option<u64>,
+    /// Smallest congestion window in bytes after loss.
+    pub minimum_window: Option<u64>,
+    /// Factor the window shrinks by on loss.
+    pub loss_reduction_factor: Option<f32>,
+}
+
+/// Congestion controllers the transport can run.
+///
+/// The quinn release in use implements NewReno only, the setting exists so
+/// configs stay valid when more controllers become available.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Congestion {
+    NewReno,
+}
+
+impl Default for Congestion {
+    fn default() -> Self {
+        Congestion::NewReno
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Docker operations running at once across all clients.
+    pub max_concurrent_operations: usize,
+    /// Streams that run until the client leaves open at once across all
+    /// clients: tunnels, datagram flows, event subscriptions, followed logs
+    /// and streamed stats. They do not count as operations.
+    pub max_concurrent_streams: usize,
+    /// Rate applied to every client and command without an override.
+    pub rate: RateConfig,
+    /// Overrides keyed by command name, such as `Stats` or `Log`.
+    pub commands: HashMap<String, RateConfig>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_concurrent_operations: 64,
+            max_concurrent_streams: 256,
+            rate: RateConfig {
+                rate: 20.0,
+                burst: 40.0,
+            },
+            commands: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    /// Serve the REST gateway over HTTP/3 on the QUIC endpoint.
+    ///
+    /// HTTP/3 needs three unidirectional streams per peer for its control
+    /// and QPACK streams. Transport settings apply to the whole endpoint,
+    /// so broker protocol clients are granted them as well, though they
+    /// never open one. The server stops any such stream as soon as it is
+    /// opened, so the cost is at most three stream receive windows of data
+    /// in flight per connection before the stop reaches the client.
+    pub http3: bool,
+    /// Bearer tokens accepted on the WebSocket listener.
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenConfig {
+    /// Name requests made with the token are attributed to.
+    pub name: String,
+    /// Hex encoded SHA-256 of the token, the token itself is never stored.
+    pub sha256: String,
+}
+
+/// One listener, on exactly one of the transports, for example a read-only
+/// QUIC listener next to the full-control one given with `--listen`:
+///
+/// ```toml
+/// [[listeners]]
+/// quic = "0.0.0.0:8001"
+/// read_only = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ListenerConfig {
+    pub quic: Option<SocketAddr>,
+    pub tls: Option<SocketAddr>,
+    pub unix: Option<PathBuf>,
+    pub websocket: Option<SocketAddr>,
+    /// Serve only the commands in `access::READ_ONLY`.
+    pub read_only: bool,
+    /// Serve only these commands, by name as in `[limits.commands]`.
+    pub commands: Option<Vec<String>>,
+}
+
+/// A team sharing the host, see `tenant::Tenants`. Clients belong to it by
+/// the name of their API key, signed token or gateway token, or their uid
+/// on the Unix socket:
+///
+/// ```toml
+/// [[tenants]]
+/// name = "blue"
+/// keys = ["blue-ci"]
+/// max_containers = 20
+/// max_memory = 8589934592
+/// max_cpus = 4.0
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TenantConfig {
+    pub name: String,
+    pub keys: Vec<String>,
+    pub tokens: Vec<String>,
+    pub uids: Vec<u32>,
+    /// Containers the tenant may have, running or not.
+    pub max_containers: Option<usize>,
+    /// Sum of the memory limits of its containers, in bytes.
+    pub max_memory: Option<u64>,
+    /// Sum of the CPU limits of its containers, in CPUs.
+    pub max_cpus: Option<f64>,
+}
+
+/// Clients outside every tenant that still may use the broker, and see
+/// the containers of all tenants:
+///
+/// ```toml
+/// [operators]
+/// keys = ["oncall"]
+/// uids = [0]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OperatorsConfig {
+    pub keys: Vec<String>,
+    pub tokens: Vec<String>,
+    pub uids: Vec<u32>,
+}
+
+/// What containers clients may create, see `policy::admit`. Images must
+/// also come from a registry in `[registry] allow`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    pub allow_privileged: bool,
+    pub allow_host_network: bool,
+    pub allow_host_pid: bool,
+    pub allow_host_ipc: bool,
+    pub allow_host_uts: bool,
+    pub allow_host_userns: bool,
+    /// Joining the namespaces of another container, with a network, PID or
+    /// IPC mode of `container:<id>`.
+    pub allow_container_namespaces: bool,
+    /// Capabilities containers may add, such as `NET_ADMIN`. `ALL` only
+    /// when listed itself.
+    pub allow_capabilities: Vec<String>,
+    /// Host devices mapped into containers.
+    pub allow_devices: bool,
+    /// Security options that lift confinement, such as
+    /// `seccomp=unconfined` or `label=disable`.
+    pub allow_unconfined: bool,
+    /// Mounting the volumes of another container with `VolumesFrom`.
+    pub allow_volumes_from: bool,
+    /// Placing containers under another cgroup with `CgroupParent`, which
+    /// escapes the limits of the one Docker picks.
+    pub allow_cgroup_parent: bool,
+    /// Image builds whose context dockerd fetches from the `remote` URL.
+    pub allow_remote_builds: bool,
+    /// Host paths containers may bind mount, with everything below them.
+    /// Empty denies bind mounts, named volumes are always allowed. Volumes
+    /// of the local driver with a `device` option count as bind mounts of
+    /// that device.
+    ///
+    /// Sources are compared with their symlinks resolved. Docker resolves
+    /// them again when the container starts, so a client that can write
+    /// below one of these paths can still swap in a symlink after the
+    /// check. List only paths clients cannot write to.
+    pub bind_paths: Vec<PathBuf>,
+    /// Images containers may be created from, such as `nginx` for any tag
+    /// of it, `nginx:1.17` or `registry.example.com/team/*` for every
+    /// repository below `team`. Empty allows any, otherwise image builds
+    /// are refused since they could tag any of these names.
+    pub images: Vec<String>,
+    /// Applied to containers that do not set them.
+    pub defaults: DefaultsConfig,
+    pub update: UpdatePolicyConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DefaultsConfig {
+    /// Memory limit in bytes.
+    pub memory: Option<u64>,
+    /// CPU limit in CPUs.
+    pub cpus: Option<f64>,
+    pub labels: HashMap<String, String>,
+}
+
+/// What resource limits clients may set with `Update`, see
+/// `policy::admit_update`:
+///
+/// ```toml
+/// [policy.update]
+/// memory_overcommit = 1.5
+///
+/// [[policy.update.caps]]
+/// tenant = "blue"
+/// max_memory = 2147483648
+/// max_cpus = 2.0
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UpdatePolicyConfig {
+    /// Caps for the first entry a client matches, none for clients that
+    /// match no entry. They bound the limits of image builds as well.
+    pub caps: Vec<CapConfig>,
+    /// How far the memory limits of all containers may add up beyond the
+    /// host's memory, 1.0 for not at all.
+    pub memory_overcommit: Option<f64>,
+    /// How far the CPU limits of all containers may add up beyond the
+    /// host's CPUs.
+    pub cpu_overcommit: Option<f64>,
+}
+
+/// Caps on the limits of a single container, for the clients of `tenant`
+/// or the role made up of the listed clients.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CapConfig {
+    pub tenant: Option<String>,
+    pub keys: Vec<String>,
+    pub tokens: Vec<String>,
+    pub uids: Vec<u32>,
+    /// Memory limit in bytes.
+    pub max_memory: Option<u64>,
+    /// CPU limit in CPUs.
+    pub max_cpus: Option<f64>,
+}
+
+/// API keys and signed tokens, see `auth::KeyStore`. Clients must
+/// authenticate once either is configured.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Hashed API keys and revoked token names, re-read when it changes.
+    pub keys_file: Option<PathBuf>,
+    /// Secret of at least 32 bytes, raw or hex encoded, tokens are signed with.
+    pub token_key_file: Option<PathBuf>,
+    /// Lifetime of tokens issued with `--issue-token`.
+    pub token_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            keys_file: None,
+            token_key_file: None,
+            token_ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Who may use the Unix socket given with `--listen-unix`.
+///
+/// The user the server runs as is always allowed. Groups match the peer's
+/// primary group only, as reported by `SO_PEERCRED`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LocalConfig {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    /// Permission bits of the socket file.
+    pub mode: u32,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        LocalConfig {
+            uids: Vec::new(),
+            gids: Vec::new(),
+            mode: 0o660,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Codecs offered to clients in order of preference, empty disables
+    /// compression.
+    pub codecs: Vec<Codec>,
+    /// Smallest encoded response frame worth compressing, in bytes.
+    pub threshold: usize,
+    /// zstd compression level, lz4 ignores it.
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            codecs: vec![Codec::Zstd, Codec::Lz4],
+            threshold: 1024,
+            level: 3,
+        }
+    }
+}
+
+/// Token bucket refilled with `rate` tokens per second up to `burst`.
+/// A rate of 0 disables the limit.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateConfig {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl TransportConfig {
+    pub fn build(&self) -> Result<quinn::TransportConfig> {
+        let mut transport = quinn::TransportConfig::default();
+        transport.stream_window_uni(0);
+        transport.datagram_receive_buffer_size(Some(self.datagram_receive_buffer.unwrap_or(1024 * 1024)));
+
+        if let Some(ms) = self.idle_timeout_ms {
+            let timeout = if ms == 0 { None } else { Some(Duration::from_millis(ms)) };
+            transport
+                .idle_timeout(timeout)
+                .map_err(|e| anyhow!("Invalid transport.idle_timeout_ms: {}", e))?;
+        }
+        if let Some(ms) = self.keep_alive_interval_ms {
+            transport.keep_alive_interval(Some(Duration::from_millis(ms)));
+        }
+        if let Some(n) = self.max_concurrent_bidi_streams {
+            transport.stream_window_bidi(n);
+        }
+        if let Some(n) = self.stream_receive_window {
+            transport.stream_receive_window(n);
+        }
+        if let Some(n) = self.receive_window {
+            transport.receive_window(n);
+        }
+
+        match self.congestion.controller {
+            Congestion::NewReno => {
+                if let Some(n) = self.congestion.initial_window {
+                    transport.initial_window(n);
+                }
+                if let Some(n) = self.congestion.minimum_window {
+                    transport.minimum_window(n);
+                }
+                if let Some(f) = self.congestion.loss_reduction_factor {
+                    transport.loss_reduction_factor(f);
+                }
+            }
+        }
+
+        Ok(transport)
+    }
+}
+
+impl Config {
+    pub async fn load(path: &Option<PathBuf>) -> Result<Config> {
+        let path = match path {
+            Some(p) => p,
+            None => return Ok(Config::default()),
+        };
+
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read config {}.", path.display()))?;
+
+        let config: Config =
+            toml::from_str(&content).with_context(|| format!("Failed to parse config {}.", path.display()))?;
+
+        // No permits would refuse every command.
+        if config.limits.max_concurrent_operations == 0 {
+            bail!("limits.max_concurrent_operations must be at least 1.");
+        }
+        if config.limits.max_concurrent_streams == 0 {
+            bail!("limits.max_concurrent_streams must be at least 1.");
+        }
+
+        Ok(config)
+    }
+}
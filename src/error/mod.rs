@@ -0,0 +1,102 @@
+//! Error codes sent to clients next to the human readable message.
+//!
+//! Handlers keep returning `anyhow::Error`; `response` works out the code
+//! from the error itself. Docker errors are classified by their bollard
+//! kind, errors raised by the broker carry their code in an `Error`.
+
+use std::fmt;
+use std::time::Duration;
+
+use bollard::errors::ErrorKind;
+use broker_proto::Protocol;
+
+pub use broker_proto::ErrorCode;
+
+/// A failure with its code, raised by the broker or decoded by the client.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    /// HTTP status returned by the container runtime, if it got that far.
+    pub status: Option<u16>,
+    /// Milliseconds after which a refused request may be retried.
+    pub retry_after_ms: Option<u64>,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Error {
+        Error {
+            code,
+            message: message.into(),
+            status: None,
+            retry_after_ms: None,
+        }
+    }
+
+    /// The error telling the client to retry after `delay`.
+    pub fn retry_after(mut self, delay: Duration) -> Error {
+        self.retry_after_ms = Some((delay.as_millis() as u64).max(1));
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Error response for `e`, with its code, upstream status and retry hint.
+pub fn response(e: &anyhow::Error) -> Protocol {
+    let (code, status) = classify(e);
+    let mut proto = Protocol::error(code, &e.to_string(), status);
+    proto.retry_after_ms = e.downcast_ref::<Error>().and_then(|e| e.retry_after_ms);
+    proto
+}
+
+/// Code and upstream HTTP status of `e`.
+pub fn classify(e: &anyhow::Error) -> (ErrorCode, Option<u16>) {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        return (e.code, e.status);
+    }
+
+    if let Some(e) = e.downcast_ref::<bollard::errors::Error>() {
+        return match e.kind() {
+            ErrorKind::DockerResponseNotFoundError { .. } => (ErrorCode::NotFound, Some(404)),
+            ErrorKind::DockerResponseConflictError { .. } => (ErrorCode::Conflict, Some(409)),
+            ErrorKind::DockerResponseBadParameterError { .. } => (ErrorCode::InvalidArgument, Some(400)),
+            // Docker answers 304 when a container already is in the requested state.
+            ErrorKind::DockerResponseNotModifiedError { .. } => (ErrorCode::Conflict, Some(304)),
+            ErrorKind::DockerResponseServerError { status_code, .. } => {
+                (status_code_of(*status_code), Some(*status_code))
+            }
+            ErrorKind::RequestTimeoutError => (ErrorCode::Timeout, None),
+            ErrorKind::IOError { .. } | ErrorKind::HyperResponseError { .. } => {
+                (ErrorCode::RuntimeUnavailable, None)
+            }
+            _ => (ErrorCode::Internal, None),
+        };
+    }
+
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            return (ErrorCode::Timeout, None);
+        }
+    }
+
+    (ErrorCode::Internal, None)
+}
+
+fn status_code_of(status: u16) -> ErrorCode {
+    match status {
+        400 => ErrorCode::InvalidArgument,
+        401 | 403 => ErrorCode::PermissionDenied,
+        404 => ErrorCode::NotFound,
+        409 => ErrorCode::Conflict,
+        408 | 504 => ErrorCode::Timeout,
+        503 => ErrorCode::RuntimeUnavailable,
+        _ => ErrorCode::Internal,
+    }
+}
@@ -0,0 +1,97 @@
+//! HTTP/3 transport of the gateway, served on the broker's own QUIC
+//! endpoint. Connections that negotiate `ALPN_H3` are handed to quinn-h3
+//! instead of the broker framing.
+
+use std::sync::Arc;
+
+extern crate anyhow;
+use anyhow::Result;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{header, Response};
+use quinn_h3::server::RecvRequest;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
+
+use crate::access::Access;
+use crate::auth::Identity;
+use crate::codec::Format;
+use crate::error::{Error, ErrorCode};
+use crate::request::MAX_HEADER_SIZE;
+use crate::session::Session;
+use crate::state::State;
+
+/// HTTP/3 draft spoken by the quinn-h3 revision in use.
+pub const ALPN_H3: &[u8] = b"h3-27";
+
+/// Serves the requests of one HTTP/3 connection.
+pub async fn serve(state: Arc<State>, conn: quinn::NewConnection, access: Access) -> Result<()> {
+    let session = Arc::new(Session {
+        identity: Identity::Address(conn.connection.remote_address().ip()),
+        flows: None,
+        format: Format::Json,
+        access,
+    });
+
+    let mut requests = quinn_h3::server::IncomingRequest::new(conn, quinn_h3::Settings::new());
+
+    while let Some(request) = requests.next().await {
+        let state = state.clone();
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(state, session, request).await {
+                error!("HTTP/3 request failed: {}", e);
+            }
+        });
+    }
+
+    info!("Connection closed.");
+    Ok(())
+}
+
+async fn handle_request(state: Arc<State>, session: Arc<Session>, request: RecvRequest) -> Result<()> {
+    let (request, sender) = request.await?;
+    let (parts, mut body) = request.into_parts();
+
+    info!(method = %parts.method, path = %parts.uri.path(), "HTTP/3 request.");
+
+    // Nothing is buffered for a client that has not authenticated yet.
+    let authorization = parts.headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let mut reply = match super::authenticate(&state, &session, authorization).await {
+        Ok(session) => match read_body(&mut body).await {
+            Ok(body) => super::call(state, session, &parts.method, parts.uri.path(), parts.uri.query(), &body).await,
+            Err(e) => super::Reply::error(&e),
+        },
+        Err(e) => super::Reply::error(&e),
+    };
+
+    let response = Response::builder()
+        .status(reply.status)
+        .header(header::CONTENT_TYPE, reply.content_type)
+        .body(())?;
+    let mut body = sender.send_response(response).await?;
+
+    while let Some(chunk) = reply.next_chunk().await {
+        body.write_all(&chunk).await?;
+    }
+    body.close().await?;
+
+    Ok(())
+}
+
+/// Reads a request body of at most `MAX_HEADER_SIZE` bytes, the most any
+/// gateway route takes.
+async fn read_body(body: &mut quinn_h3::body::RecvBody) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Error::new(ErrorCode::InvalidArgument, format!("Failed to read request body: {}", e)))?;
+        if buf.len() + chunk.len() > MAX_HEADER_SIZE {
+            return Err(Error::new(
+                ErrorCode::InvalidArgument,
+                format!("Request body exceeds {} bytes.", MAX_HEADER_SIZE),
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
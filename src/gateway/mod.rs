@@ -0,0 +1,414 @@
+//! REST style gateway onto the broker commands, for clients that speak
+//! HTTP instead of the broker framing.
+//!
+//! A route maps onto a command, which runs through `request::handle_request`
+//! like any other request. Its JSON frames become the HTTP response: the
+//! first frame decides the status, log output is passed through as text and
+//! every other body is sent as one JSON document per line.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+extern crate anyhow;
+use anyhow::Result;
+use bollard::container::*;
+use broker_proto::{Arguments, Body, CommandType, Protocol};
+use hyper::{Method, StatusCode};
+use serde_json::json;
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::capabilities::PROTOCOL_VERSION;
+use crate::codec::Format;
+use crate::error::{Error, ErrorCode};
+use crate::request;
+use crate::session::Session;
+use crate::state::State;
+
+pub mod h3;
+pub mod ws;
+
+/// Command and argument served by a route.
+pub type Route = (CommandType, Option<Arguments>);
+
+/// Maps an HTTP request onto a broker command.
+///
+/// Fails with `NotFound` for unknown paths and `InvalidArgument` for
+/// unsupported methods and malformed query strings or bodies.
+pub fn route(method: &Method, path: &str, query: Option<&str>, body: &[u8]) -> Result<Route, Error> {
+    let segments = path.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    let query = Query::parse(query.unwrap_or(""));
+
+    let route = match (method, segments.as_slice()) {
+        (&Method::GET, []) | (&Method::GET, ["hello"]) => (
+            CommandType::Hello,
+            Some(Arguments::Hello { protocol: PROTOCOL_VERSION }),
+        ),
+        (&Method::GET, ["system"]) => (CommandType::System, None),
+        (&Method::GET, ["containers"]) => (CommandType::List, None),
+        (&Method::POST, ["containers"]) => (
+            CommandType::Create,
+            Some(Arguments::Create {
+                config: json_body(body)?,
+                options: query.get("name").map(|name| CreateContainerOptions { name: name.to_owned() }),
+            }),
+        ),
+        (&Method::POST, ["containers", "prune"]) => (
+            CommandType::Prune,
+            Some(Arguments::Prune {
+                options: match query.get("filters") {
+                    Some(filters) => Some(PruneContainersOptions {
+                        filters: serde_json::from_str(filters).map_err(|_| invalid("Invalid filters."))?,
+                    }),
+                    None => None,
+                },
+                dry_run: query.flag("dry_run")?,
+            }),
+        ),
+        (&Method::POST, ["containers", "stop"]) => (
+            CommandType::Stop,
+            Some(Arguments::StopMatching {
+                filters: match query.get("filters") {
+                    Some(filters) => serde_json::from_str(filters).map_err(|_| invalid("Invalid filters."))?,
+                    None => Default::default(),
+                },
+                options: query.number("t")?.map(|t| StopContainerOptions { t }),
+                dry_run: query.flag("dry_run")?,
+            }),
+        ),
+        (&Method::GET, ["containers", name]) => (
+            CommandType::Container,
+            Some(Arguments::InspectContainer {
+                name: name.to_string(),
+                options: Some(InspectContainerOptions { size: query.flag("size")? }),
+            }),
+        ),
+        (&Method::DELETE, ["containers", name]) => (
+            CommandType::Remove,
+            Some(Arguments::Remove {
+                name: name.to_string(),
+                options: Some(RemoveContainerOptions {
+                    force: query.flag("force")?,
+                    v: query.flag("v")?,
+                    ..Default::default()
+                }),
+                dry_run: query.flag("dry_run")?,
+            }),
+        ),
+        (&Method::GET, ["containers", name, "changes"]) => (
+            CommandType::Change,
+            Some(Arguments::ContainerChanges { name: name.to_string() }),
+        ),
+        (&Method::GET, ["containers", name, "stats"]) => (
+            CommandType::Stats,
+            Some(Arguments::Stats {
+                name: name.to_string(),
+                options: Some(StatsOptions { stream: false }),
+            }),
+        ),
+        (&Method::GET, ["containers", name, "top"]) => (
+            CommandType::Top,
+            Some(Arguments::Top {
+                name: name.to_string(),
+                options: query.get("ps_args").map(|ps_args| TopOptions { ps_args: ps_args.to_owned() }),
+            }),
+        ),
+        (&Method::GET, ["containers", name, "logs"]) => (
+            CommandType::Log,
+            Some(Arguments::Logs {
+                name: name.to_string(),
+                options: Some(LogsOptions {
+                    follow: query.flag("follow")?,
+                    stdout: true,
+                    stderr: true,
+                    timestamps: query.flag("timestamps")?,
+                    tail: query.get("tail").unwrap_or("all").to_owned(),
+                    ..Default::default()
+                }),
+            }),
+        ),
+        (&Method::POST, ["containers", name, "start"]) => (
+            CommandType::Start,
+            Some(Arguments::Start { name: name.to_string(), options: None }),
+        ),
+        (&Method::POST, ["containers", name, "stop"]) => (
+            CommandType::Stop,
+            Some(Arguments::Stop {
+                name: name.to_string(),
+                options: query.number("t")?.map(|t| StopContainerOptions { t }),
+                dry_run: query.flag("dry_run")?,
+            }),
+        ),
+        (&Method::POST, ["containers", name, "kill"]) => (
+            CommandType::Kill,
+            Some(Arguments::Kill {
+                name: name.to_string(),
+                options: Some(KillContainerOptions {
+                    signal: query.get("signal").unwrap_or("SIGKILL").to_owned(),
+                }),
+            }),
+        ),
+        (&Method::POST, ["containers", name, "restart"]) => (
+            CommandType::Restart,
+            Some(Arguments::Restart {
+                name: name.to_string(),
+                options: query.number("t")?.map(|t| RestartContainerOptions { t }),
+            }),
+        ),
+        (&Method::POST, ["containers", name, "update"]) => (
+            CommandType::Update,
+            Some(Arguments::Update {
+                name: name.to_string(),
+                options: json_body(body)?,
+            }),
+        ),
+        (_, []) | (_, ["hello"]) | (_, ["system"]) | (_, ["containers", ..]) => {
+            return Err(invalid(format!("Method {} not allowed on {}.", method, path)))
+        }
+        _ => return Err(Error::new(ErrorCode::NotFound, format!("No route for {}.", path))),
+    };
+
+    Ok(route)
+}
+
+/// HTTP status answering an error with `code`.
+pub fn status_of(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Conflict => StatusCode::CONFLICT,
+        ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorCode::InvalidArgument => StatusCode::BAD_REQUEST,
+        ErrorCode::RuntimeUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Response to one HTTP request, its body produced as the command runs.
+pub struct Reply {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    first: Option<Vec<u8>>,
+    frames: Option<Frames>,
+}
+
+impl Reply {
+    fn error(e: &Error) -> Reply {
+        Reply {
+            status: status_of(e.code),
+            content_type: "application/json",
+            first: Some(error_json(e.code, &e.message, e.status, e.retry_after_ms)),
+            frames: None,
+        }
+    }
+
+    /// Next part of the body, `None` once the command finished.
+    pub async fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        if let Some(chunk) = self.first.take() {
+            return Some(chunk);
+        }
+
+        loop {
+            let frame = self.frames.as_mut()?.next().await?;
+            let chunk = chunk_of(&frame);
+            if !chunk.is_empty() {
+                return Some(chunk);
+            }
+        }
+    }
+}
+
+/// Session an HTTP request runs in. Once the server requires
+/// authentication, every request carries `Authorization: Bearer` with an
+/// API key or signed token.
+pub async fn authenticate(state: &State, session: &Arc<Session>, authorization: Option<&str>) -> Result<Arc<Session>, Error> {
+    if !state.keys.enabled() {
+        return Ok(session.clone());
+    }
+
+    let credential = authorization
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| &value["Bearer ".len()..])
+        .ok_or_else(|| Error::new(ErrorCode::PermissionDenied, "Missing bearer token."))?;
+
+    match state.keys.authenticate(credential).await {
+        Ok(identity) => Ok(Arc::new(Session {
+            identity,
+            flows: None,
+            format: session.format,
+            access: session.access.clone(),
+        })),
+        Err(e) => Err(e
+            .downcast::<Error>()
+            .unwrap_or_else(|e| Error::new(ErrorCode::Internal, e.to_string()))),
+    }
+}
+
+/// Runs the command behind an HTTP request on behalf of `session`.
+pub async fn call(state: Arc<State>, session: Arc<Session>, method: &Method, path: &str, query: Option<&str>, body: &[u8]) -> Reply {
+    let (cmd_type, argument) = match route(method, path, query, body) {
+        Ok(route) => route,
+        Err(e) => return Reply::error(&e),
+    };
+    let streams_text = matches!(cmd_type, CommandType::Log);
+
+    let header = match Format::Json.encode(&Protocol::command(cmd_type, argument)) {
+        Ok(header) => header,
+        Err(e) => return Reply::error(&Error::new(ErrorCode::Internal, e.to_string())),
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut recv = &header[..];
+        let mut send = ChannelWriter(tx);
+        if let Err(e) = request::handle_request(&state, &session, &mut recv, &mut send).await {
+            error!("Gateway request failed: {}", e);
+        }
+    });
+
+    let mut frames = Frames { rx, buf: Vec::new() };
+    let first = match frames.next().await {
+        Some(frame) => frame,
+        None => return Reply::error(&Error::new(ErrorCode::Internal, "Request ended without a response.")),
+    };
+
+    let status = match &first.error {
+        Some(_) => status_of(first.code.unwrap_or(ErrorCode::Internal)),
+        None => StatusCode::OK,
+    };
+    let content_type = if streams_text && first.error.is_none() {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/json"
+    };
+
+    Reply {
+        status,
+        content_type,
+        first: Some(chunk_of(&first)),
+        frames: Some(frames),
+    }
+}
+
+fn chunk_of(frame: &Protocol) -> Vec<u8> {
+    if let Some(message) = &frame.error {
+        return error_json(frame.code.unwrap_or(ErrorCode::Internal), message, frame.status, frame.retry_after_ms);
+    }
+
+    match &frame.body {
+        Body::LogOutput(lines) => lines.iter().map(|l| l.to_string()).collect::<String>().into_bytes(),
+        body => {
+            let mut chunk = serde_json::to_vec(body).unwrap_or_default();
+            chunk.push(b'\n');
+            chunk
+        }
+    }
+}
+
+fn error_json(code: ErrorCode, message: &str, status: Option<u16>, retry_after_ms: Option<u64>) -> Vec<u8> {
+    let mut chunk = json!({
+        "code": code,
+        "message": message,
+        "status": status,
+        "retry_after_ms": retry_after_ms,
+    })
+    .to_string()
+    .into_bytes();
+    chunk.push(b'\n');
+    chunk
+}
+
+fn json_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|e| invalid(format!("Invalid request body: {}", e)))
+}
+
+fn invalid<S: Into<String>>(message: S) -> Error {
+    Error::new(ErrorCode::InvalidArgument, message)
+}
+
+/// Decoded query string parameters.
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: &str) -> Query {
+        Query(url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// `?key`, `?key=1` and `?key=true` are set, a missing key is not.
+    fn flag(&self, key: &str) -> Result<bool, Error> {
+        match self.get(key) {
+            None | Some("0") | Some("false") => Ok(false),
+            Some("") | Some("1") | Some("true") => Ok(true),
+            Some(value) => Err(invalid(format!("Invalid value {} for {}.", value, key))),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, Error> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(format!("Invalid value {} for {}.", value, key))),
+        }
+    }
+}
+
+/// JSON frames written by `handle_request`, one per line.
+struct Frames {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Frames {
+    async fn next(&mut self) -> Option<Protocol> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|b| *b == b'\n') {
+                let line = self.buf.drain(..=i).collect::<Vec<_>>();
+                match serde_json::from_slice(&line) {
+                    Ok(frame) => return Some(frame),
+                    Err(e) => {
+                        error!("Invalid gateway frame: {}", e);
+                        return None;
+                    }
+                }
+            }
+
+            self.buf.extend_from_slice(&self.rx.recv().await?);
+        }
+    }
+}
+
+/// Hands whatever `handle_request` writes to the HTTP side.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.0.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        match self.0.try_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
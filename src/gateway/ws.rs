@@ -0,0 +1,228 @@
+//! WebSocket transport for browser dashboards, over TLS with the broker's
+//! own certificate.
+//!
+//! Every binary message is one msgpack request header, followed by any
+//! upload, and every response frame comes back as its own binary message. A
+//! socket runs one request at a time: followed logs, streamed stats and
+//! events hold it until they end or the client closes it, so dashboards
+//! open one socket per subscription.
+//!
+//! Clients authenticate with a bearer token from `[[gateway.tokens]]`, or an
+//! API key or signed token from `[auth]`, sent as `Authorization: Bearer
+//! <token>` or, since browsers cannot set headers on WebSockets, as the
+//! `access_token` query parameter.
+
+use std::{net::SocketAddr, sync::Arc};
+
+extern crate anyhow;
+use anyhow::Result;
+use broker_proto::Protocol;
+use futures::{SinkExt, StreamExt, TryFutureExt};
+use hyper::{header, StatusCode};
+use ring::digest;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument as _;
+
+use super::{ChannelWriter, Query};
+use crate::access::Access;
+use crate::auth::Identity;
+use crate::codec::Format;
+use crate::config::TokenConfig;
+use crate::error::{self, ErrorCode};
+use crate::request;
+use crate::security;
+use crate::server::Opt;
+use crate::session::Session;
+use crate::state::State;
+
+/// Binds a WebSocket listener on `listen`.
+pub async fn bind(options: &Opt, listen: &SocketAddr) -> Result<(SocketAddr, TcpListener, TlsAcceptor)> {
+    let config = security::tls_config(&options.key, &options.cert, &[b"http/1.1"]).await?;
+    let listener = TcpListener::bind(listen).await?;
+    let addr = listener.local_addr()?;
+    info!("Listening on {} (WebSocket).", addr);
+
+    Ok((addr, listener, TlsAcceptor::from(Arc::new(config))))
+}
+
+pub async fn serve(state: Arc<State>, mut listener: TcpListener, acceptor: TlsAcceptor, access: Access) -> Result<()> {
+    let mut incoming = listener.incoming();
+    while let Some(tcp) = incoming.next().await {
+        let tcp = match tcp {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                error!("Accept failed: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(
+            handle_connection(state.clone(), tcp, acceptor.clone(), access.clone()).unwrap_or_else(move |e| {
+                error!("Connection failed: {reason}", reason = e.to_string())
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(state: Arc<State>, tcp: TcpStream, acceptor: TlsAcceptor, access: Access) -> Result<()> {
+    let remote = tcp.peer_addr()?;
+    tcp.set_nodelay(true)?;
+    let tls = acceptor.accept(tcp).await?;
+
+    let tokens = &state.config.gateway.tokens;
+    // API keys and signed tokens are checked once the handshake is done,
+    // the key store may have to read its file first.
+    let keys = state.keys.enabled();
+    let mut credential = None;
+    let ws = tokio_tungstenite::accept_hdr_async(tls, |request: &Request, response: Response| {
+        match bearer(request) {
+            Some(token) if keys || known(tokens, &token).is_some() => {
+                credential = Some(token);
+                Ok(response)
+            }
+            _ => Err(unauthorized()),
+        }
+    })
+    .await;
+
+    let credential = match credential {
+        Some(credential) => credential,
+        None => {
+            warn!(remote = %remote, "WebSocket client not authenticated.");
+            return Ok(());
+        }
+    };
+    let mut ws = ws?;
+
+    let identity = match known(tokens, &credential) {
+        Some(name) => Identity::Token(name),
+        None => match state.keys.authenticate(&credential).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                warn!(remote = %remote, "WebSocket client not authenticated: {}", e);
+                ws.send(Message::Binary(Format::Msgpack.encode(&error::response(&e))?)).await?;
+                ws.close(None).await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let span = info_span!("connection", remote = %remote, client = %identity, transport = "websocket");
+    let session = Arc::new(Session {
+        identity,
+        flows: None,
+        format: Format::Msgpack,
+        access,
+    });
+
+    serve_socket(state, session, ws).instrument(span).await
+}
+
+async fn serve_socket<S>(state: Arc<State>, session: Arc<Session>, ws: tokio_tungstenite::WebSocketStream<S>) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    info!("Established");
+    let (mut sink, mut source) = ws.split();
+
+    while let Some(message) = source.next().await {
+        let header = match message? {
+            Message::Binary(header) => header,
+            Message::Text(_) => {
+                let resp = Protocol::error(ErrorCode::InvalidArgument, "Requests must be binary msgpack messages.", None);
+                sink.send(Message::Binary(Format::Msgpack.encode(&resp)?)).await?;
+                continue;
+            }
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+
+        // ChannelWriter takes every write whole and `handle_request` writes
+        // each frame at once, so every chunk is exactly one frame.
+        let (tx, mut rx) = mpsc::channel(16);
+        let handle = {
+            let state = state.clone();
+            let session = session.clone();
+            async move {
+                let mut recv = &header[..];
+                let mut send = ChannelWriter(tx);
+                request::handle_request(&state, &session, &mut recv, &mut send).await
+            }
+            .instrument(info_span!("Request"))
+        };
+        tokio::pin!(handle);
+
+        let mut done = false;
+        loop {
+            tokio::select! {
+                result = &mut handle, if !done => {
+                    done = true;
+                    match result {
+                        Ok(()) => info!("Complete."),
+                        Err(e) => error!("Failed: {reason}.", reason = e.to_string()),
+                    }
+                }
+                frame = rx.recv() => match frame {
+                    Some(frame) => sink.send(Message::Binary(frame)).await?,
+                    None => break,
+                },
+                // Dropping the request ends a subscription the client left.
+                message = source.next() => match message {
+                    Some(Ok(Message::Binary(_))) | Some(Ok(Message::Text(_))) => {
+                        let resp = Protocol::error(ErrorCode::Conflict, "A request is already running on this socket.", None);
+                        sink.send(Message::Binary(Format::Msgpack.encode(&resp)?)).await?;
+                    }
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        info!("Connection closed.");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    info!("Connection closed.");
+    Ok(())
+}
+
+/// Bearer token the handshake request carries.
+fn bearer(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].to_owned())
+        .or_else(|| {
+            Query::parse(request.uri().query().unwrap_or(""))
+                .get("access_token")
+                .map(str::to_owned)
+        })
+}
+
+/// Name of `token` if it is one of `tokens`.
+fn known(tokens: &[TokenConfig], token: &str) -> Option<String> {
+    let hash = hex::encode(digest::digest(&digest::SHA256, token.trim().as_bytes()));
+
+    tokens
+        .iter()
+        .find(|t| t.sha256.eq_ignore_ascii_case(&hash))
+        .map(|t| t.name.clone())
+}
+
+fn unauthorized() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("Missing or unknown bearer token.".into()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    response
+}
@@ -0,0 +1,24 @@
+//! Library side of the broker. It holds the server, used by the
+//! `quic-server` binary and the integration tests, and the client used by
+//! the `broker` command line client and other Rust tooling.
+
+pub mod access;
+pub mod auth;
+pub mod capabilities;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod gateway;
+pub mod limits;
+pub mod metrics;
+pub mod mux;
+pub mod policy;
+pub mod registry;
+pub mod request;
+pub mod security;
+pub mod server;
+pub mod session;
+pub mod state;
+pub mod tenant;
+pub mod tunnel;
@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::auth::Identity;
+use crate::config::{LimitsConfig, RateConfig};
+
+/// Buckets kept before the least recently used ones are dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    /// When the bucket was last used.
+    updated: Instant,
+}
+
+impl Bucket {
+    fn tokens_at(&self, rate: &RateConfig, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate.rate).min(rate.burst)
+    }
+
+    fn refill(&mut self, rate: &RateConfig, now: Instant) {
+        self.tokens = self.tokens_at(rate, now);
+        self.updated = now;
+    }
+}
+
+/// Token buckets per client identity and command.
+pub struct RateLimiter {
+    default: RateConfig,
+    commands: HashMap<String, RateConfig>,
+    buckets: Mutex<HashMap<(Identity, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitsConfig) -> RateLimiter {
+        RateLimiter {
+            default: config.rate,
+            commands: config.commands.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `command`, or returns how long until one is available.
+    pub fn check(&self, identity: &Identity, command: &str) -> Result<(), Duration> {
+        let rate = self.commands.get(command).unwrap_or(&self.default);
+        if rate.rate <= 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let key = (identity.clone(), command.to_owned());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate.burst,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate.rate))
+        }
+    }
+
+    /// Drops full buckets, which a new one would replace as they are, then
+    /// the least recently used ones until half of `MAX_BUCKETS` are left,
+    /// so sweeps stay rare however many clients are active.
+    fn evict(&self, buckets: &mut HashMap<(Identity, String), Bucket>, now: Instant) {
+        buckets.retain(|(_, command), bucket| {
+            let rate = self.commands.get(command).unwrap_or(&self.default);
+            bucket.tokens_at(rate, now) < rate.burst
+        });
+
+        let keep = MAX_BUCKETS / 2;
+        if buckets.len() > keep {
+            let mut used = buckets.values().map(|bucket| bucket.updated).collect::<Vec<_>>();
+            used.sort_unstable();
+            let cutoff = used[buckets.len() - keep - 1];
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Counters kept for the lifetime of the server.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tracing::info;
+
+/// How often `report` logs the counters.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    compressed_frames: AtomicU64,
+    /// Size of compressed frames before compression.
+    original_bytes: AtomicU64,
+    /// Size of compressed frames on the wire, envelope included.
+    compressed_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_compression(&self, original: usize, compressed: usize) {
+        self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        self.original_bytes.fetch_add(original as u64, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn compressed_frames(&self) -> u64 {
+        self.compressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Original over compressed size of every frame compressed so far.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let compressed = self.compressed_bytes.load(Ordering::Relaxed);
+        if compressed == 0 {
+            return None;
+        }
+
+        Some(self.original_bytes.load(Ordering::Relaxed) as f64 / compressed as f64)
+    }
+
+    /// Logs the compression counters every `REPORT_INTERVAL`, skipping
+    /// intervals in which nothing was compressed.
+    pub async fn report(&self) {
+        let mut interval = tokio::time::interval(REPORT_INTERVAL);
+        let mut reported = 0;
+
+        loop {
+            interval.tick().await;
+
+            let frames = self.compressed_frames();
+            if frames == reported {
+                continue;
+            }
+            reported = frames;
+
+            if let Some(ratio) = self.compression_ratio() {
+                info!(compressed_frames = frames, compression_ratio = ratio, "Compression.");
+            }
+        }
+    }
+}
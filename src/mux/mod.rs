@@ -0,0 +1,346 @@
+//! Bidirectional streams multiplexed over one ordered byte stream, for
+//! transports that have no streams of their own such as TLS over TCP.
+//!
+//! Every frame belongs to one stream:
+//!
+//! ```text
+//! stream: u32 BE | flags: u8 | length: u32 BE | payload
+//! ```
+//!
+//! Only clients open streams, by sending the first frame on an ID above
+//! every ID they used before. Frames for lower IDs the server no longer
+//! knows are late frames of closed streams and are dropped. A stream opened
+//! past `MAX_STREAMS` is reset right away.
+//!
+//! `FIN` ends the sender's half of a stream, `RESET` aborts it. There is no
+//! per-stream flow control: a stream whose reader falls behind holds up the
+//! whole connection once its buffer is full.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Last frame the sender writes on a stream.
+pub const FIN: u8 = 1;
+/// Aborts a stream in both directions.
+pub const RESET: u8 = 2;
+
+/// Largest payload of a single frame.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+const HEADER: usize = 9;
+
+/// Chunks buffered per stream before the connection waits for its reader.
+const STREAM_BUFFER: usize = 16;
+
+/// Streams a client may have open on the server at once.
+pub const MAX_STREAMS: usize = 100;
+
+struct Frame {
+    stream: u32,
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+enum Outgoing {
+    Frame(Frame),
+    /// Stops writing and closes the underlying stream.
+    Close,
+}
+
+type Streams = Arc<Mutex<HashMap<u32, mpsc::Sender<io::Result<Vec<u8>>>>>>;
+
+/// Client side of a multiplexed connection.
+pub struct Connection {
+    outgoing: mpsc::Sender<Outgoing>,
+    streams: Streams,
+    next_id: AtomicU32,
+}
+
+impl Connection {
+    /// Opens a new stream. The server learns about it with the first write.
+    pub fn open(&self) -> (SendStream, RecvStream) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        self.streams.lock().unwrap().insert(id, tx);
+
+        (SendStream::new(id, self.outgoing.clone(), None), RecvStream::new(rx))
+    }
+
+    /// Closes the connection once frames already written are sent.
+    pub fn close(&self) {
+        let _ = self.outgoing.clone().try_send(Outgoing::Close);
+    }
+}
+
+/// Starts the client side of a connection on `io`.
+pub fn connect<S>(io: S) -> Connection
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let streams = Streams::default();
+    let outgoing = start(io, streams.clone(), None);
+
+    Connection {
+        outgoing,
+        streams,
+        next_id: AtomicU32::new(0),
+    }
+}
+
+/// Starts the server side of a connection on `io` and returns the streams
+/// clients open, in the order they are opened.
+pub fn accept<S>(io: S) -> mpsc::Receiver<(SendStream, RecvStream)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    start(io, Streams::default(), Some(tx));
+    rx
+}
+
+fn start<S>(io: S, streams: Streams, acceptor: Option<mpsc::Sender<(SendStream, RecvStream)>>) -> mpsc::Sender<Outgoing>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(io);
+    let (outgoing, rx) = mpsc::channel(STREAM_BUFFER);
+
+    tokio::spawn(write_frames(writer, rx));
+    tokio::spawn({
+        let outgoing = outgoing.clone();
+        async move {
+            if let Err(e) = read_frames(reader, &streams, acceptor, outgoing).await {
+                debug!("Multiplexed connection failed: {}", e);
+            }
+            // Ends every stream still open.
+            streams.lock().unwrap().clear();
+        }
+    });
+
+    outgoing
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(mut writer: W, mut rx: mpsc::Receiver<Outgoing>) {
+    while let Some(Outgoing::Frame(frame)) = rx.recv().await {
+        let mut header = [0u8; HEADER];
+        header[..4].copy_from_slice(&frame.stream.to_be_bytes());
+        header[4] = frame.flags;
+        header[5..].copy_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+
+        if writer.write_all(&header).await.is_err() || writer.write_all(&frame.payload).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    streams: &Streams,
+    mut acceptor: Option<mpsc::Sender<(SendStream, RecvStream)>>,
+    outgoing: mpsc::Sender<Outgoing>,
+) -> io::Result<()> {
+    let mut header = [0u8; HEADER];
+    // Lowest ID a client may open a stream on.
+    let mut next_id = 0u64;
+    // Every server side stream holds a clone until its sending half drops.
+    let open = Arc::new(());
+
+    loop {
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let flags = header[4];
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame payload too large."));
+        }
+
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        let stream = streams.lock().unwrap().get(&id).cloned();
+        let mut stream = match (stream, &mut acceptor) {
+            (Some(stream), _) => stream,
+            (None, Some(acceptor)) if flags & RESET == 0 && u64::from(id) >= next_id => {
+                next_id = u64::from(id) + 1;
+                if Arc::strong_count(&open) > MAX_STREAMS {
+                    debug!(stream = id, "Too many streams, resetting.");
+                    let frame = Frame {
+                        stream: id,
+                        flags: RESET,
+                        payload: Vec::new(),
+                    };
+                    if outgoing.clone().send(Outgoing::Frame(frame)).await.is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+
+                let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+                streams.lock().unwrap().insert(id, tx.clone());
+                let send = SendStream::new(id, outgoing.clone(), Some(open.clone()));
+                let pair = (send, RecvStream::new(rx));
+                if acceptor.send(pair).await.is_err() {
+                    return Ok(());
+                }
+                tx
+            }
+            // Late frames of a stream already closed on this side.
+            _ => continue,
+        };
+
+        if flags & RESET != 0 {
+            let _ = stream.send(Err(io::ErrorKind::ConnectionReset.into())).await;
+            streams.lock().unwrap().remove(&id);
+            continue;
+        }
+
+        if !payload.is_empty() && stream.send(Ok(payload)).await.is_err() {
+            // The reader went away, drop whatever else arrives for it.
+            streams.lock().unwrap().remove(&id);
+            continue;
+        }
+
+        if flags & FIN != 0 {
+            streams.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// Sending half of a multiplexed stream.
+///
+/// Shutting it down sends `FIN`, dropping it before that sends `RESET`.
+pub struct SendStream {
+    id: u32,
+    outgoing: mpsc::Sender<Outgoing>,
+    finished: bool,
+    /// Counts the stream against `MAX_STREAMS` on the server side.
+    _open: Option<Arc<()>>,
+}
+
+impl SendStream {
+    fn new(id: u32, outgoing: mpsc::Sender<Outgoing>, open: Option<Arc<()>>) -> SendStream {
+        SendStream {
+            id,
+            outgoing,
+            finished: false,
+            _open: open,
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context, flags: u8, payload: &[u8]) -> Poll<io::Result<()>> {
+        match self.outgoing.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let frame = Frame {
+            stream: self.id,
+            flags,
+            payload: payload.to_vec(),
+        };
+        match self.outgoing.try_send(Outgoing::Frame(frame)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl AsyncWrite for SendStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.finished {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = buf.len().min(MAX_PAYLOAD);
+        match self.poll_send(cx, 0, &buf[..n]) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(n)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.finished {
+            return Poll::Ready(Ok(()));
+        }
+
+        let result = self.poll_send(cx, FIN, &[]);
+        if let Poll::Ready(Ok(())) = result {
+            self.finished = true;
+        }
+        result
+    }
+}
+
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        if !self.finished {
+            let frame = Frame {
+                stream: self.id,
+                flags: RESET,
+                payload: Vec::new(),
+            };
+            let _ = self.outgoing.try_send(Outgoing::Frame(frame));
+        }
+    }
+}
+
+/// Receiving half of a multiplexed stream, reads 0 bytes after `FIN`.
+pub struct RecvStream {
+    incoming: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl RecvStream {
+    fn new(incoming: mpsc::Receiver<io::Result<Vec<u8>>>) -> RecvStream {
+        RecvStream {
+            incoming,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for RecvStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.len().min(self.chunk.len() - self.pos);
+                buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Admission policy for containers clients create, the images they build
+//! and the limits they update.
+//!
+//! Every violation in a request is reported at once, so a client can fix
+//! its config in one go. A config that passes gets the configured defaults
+//! for whatever it leaves unset.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+extern crate anyhow;
+use anyhow::{bail, Result};
+use bollard::container::{Config, HostConfig, InspectContainerOptions, ListContainersOptions, UpdateContainerOptions};
+use bollard::image::BuildImageOptions;
+use bollard::Docker;
+use tokio::fs;
+
+use crate::auth::Identity;
+use crate::config::{CapConfig, PolicyConfig, TenantConfig};
+use crate::error::{Error, ErrorCode};
+use crate::registry::{Reference, Registries};
+
+/// CPU period Docker uses when a quota is set without one, in microseconds.
+const DEFAULT_CPU_PERIOD: f64 = 100_000.0;
+
+/// Checks `config` against `policy` and fills in its defaults.
+///
+/// Fails with a `PermissionDenied` error listing every violation.
+pub async fn admit(policy: &PolicyConfig, registries: &Registries, config: &mut Config<String>) -> Result<()> {
+    let mut violations = Vec::new();
+
+    if let Some(image) = &config.image {
+        if let Err(e) = registries.check_image(image) {
+            violations.push(e.to_string());
+        }
+        if !policy.images.is_empty() && !policy.images.iter().any(|pattern| image_matches(pattern, image)) {
+            violations.push(format!("Image {} is not allowed.", image));
+        }
+    }
+
+    if let Some(host_config) = &config.host_config {
+        if host_config.privileged == Some(true) && !policy.allow_privileged {
+            violations.push("Privileged mode is not allowed.".to_owned());
+        }
+        if host_config.network_mode.as_deref() == Some("host") && !policy.allow_host_network {
+            violations.push("Host networking is not allowed.".to_owned());
+        }
+        if host_config.pid_mode.as_deref() == Some("host") && !policy.allow_host_pid {
+            violations.push("Host PID namespace is not allowed.".to_owned());
+        }
+        if host_config.ipc_mode.as_deref() == Some("host") && !policy.allow_host_ipc {
+            violations.push("Host IPC namespace is not allowed.".to_owned());
+        }
+        if host_config.uts_mode.as_deref() == Some("host") && !policy.allow_host_uts {
+            violations.push("Host UTS namespace is not allowed.".to_owned());
+        }
+        if host_config.userns_mode.as_deref() == Some("host") && !policy.allow_host_userns {
+            violations.push("Host user namespace is not allowed.".to_owned());
+        }
+
+        if !policy.allow_container_namespaces {
+            let modes = [
+                ("network", &host_config.network_mode),
+                ("PID", &host_config.pid_mode),
+                ("IPC", &host_config.ipc_mode),
+            ];
+            for (namespace, mode) in modes.iter() {
+                if mode.as_deref().map_or(false, |m| m.starts_with("container:")) {
+                    violations.push(format!(
+                        "Joining the {} namespace of another container is not allowed.",
+                        namespace
+                    ));
+                }
+            }
+        }
+
+        for capability in host_config.cap_add.iter().flatten() {
+            let name = capability_name(capability);
+            if !policy.allow_capabilities.iter().any(|allowed| capability_name(allowed) == name) {
+                violations.push(format!("Capability {} is not allowed.", capability));
+            }
+        }
+        if host_config.devices.as_ref().map_or(false, |d| !d.is_empty()) && !policy.allow_devices {
+            violations.push("Device mappings are not allowed.".to_owned());
+        }
+        if !policy.allow_unconfined {
+            for option in host_config.security_opt.iter().flatten().filter(|o| unconfined(o)) {
+                violations.push(format!("Security option {} is not allowed.", option));
+            }
+        }
+        if host_config.volumes_from.as_ref().map_or(false, |v| !v.is_empty()) && !policy.allow_volumes_from {
+            violations.push("Mounting the volumes of other containers is not allowed.".to_owned());
+        }
+        if host_config.cgroup_parent.as_ref().map_or(false, |p| !p.is_empty()) && !policy.allow_cgroup_parent {
+            violations.push("Setting the parent cgroup is not allowed.".to_owned());
+        }
+
+        for source in bind_sources(host_config) {
+            let mut allowed = false;
+            for path in &policy.bind_paths {
+                if path_within(Path::new(source), path).await {
+                    allowed = true;
+                    break;
+                }
+            }
+            if !allowed {
+                violations.push(format!("Bind mount of {} is not allowed.", source));
+            }
+        }
+    }
+
+    reject(violations)?;
+
+    apply_defaults(policy, config);
+    Ok(())
+}
+
+/// Checks the options of an image build by `identity` against `policy` and
+/// fills in the default limits.
+///
+/// A built image can be tagged as any name, so builds are refused outright
+/// while `policy.images` restricts what containers run. Resource limits are
+/// held to the client's cap in `policy.update.caps`.
+///
+/// Fails with a `PermissionDenied` error listing every violation.
+pub fn admit_build(
+    policy: &PolicyConfig,
+    registries: &Registries,
+    identity: &Identity,
+    options: &mut BuildImageOptions<String>,
+) -> Result<()> {
+    let mut violations = Vec::new();
+
+    if !policy.images.is_empty() {
+        violations.push("Image builds are not allowed while images are restricted.".to_owned());
+    }
+    if !options.t.is_empty() {
+        if let Err(e) = registries.check_image(&options.t) {
+            violations.push(e.to_string());
+        }
+    }
+    if !options.remote.is_empty() && !policy.allow_remote_builds {
+        violations.push("Building from a remote context is not allowed.".to_owned());
+    }
+    if options.networkmode == "host" && !policy.allow_host_network {
+        violations.push("Host networking is not allowed.".to_owned());
+    }
+    if options.networkmode.starts_with("container:") && !policy.allow_container_namespaces {
+        violations.push("Joining the network namespace of another container is not allowed.".to_owned());
+    }
+
+    let period = options.cpuperiod.filter(|p| *p > 0).map_or(DEFAULT_CPU_PERIOD, |p| p as f64);
+    let memory = options.memory.unwrap_or(0);
+    let cpus = options.cpuquota.map_or(0.0, |q| q as f64 / period);
+    if let Some(cap) = policy.update.caps.iter().find(|cap| applies(cap, identity, None)) {
+        if let Some(max) = cap.max_memory {
+            if memory == 0 || memory > max {
+                violations.push(format!("Build memory limit must be at most {} bytes.", max));
+            }
+        }
+        if let Some(max) = cap.max_cpus {
+            if cpus == 0.0 || cpus > max {
+                violations.push(format!("Build CPU limit must be at most {} CPUs.", max));
+            }
+        }
+    }
+
+    reject(violations)?;
+
+    let defaults = &policy.defaults;
+    if memory == 0 {
+        options.memory = defaults.memory;
+    }
+    if let Some(default) = defaults.cpus.filter(|_| cpus == 0.0) {
+        options.cpuperiod = Some(period as u64);
+        options.cpuquota = Some((default * period) as u64);
+    }
+
+    Ok(())
+}
+
+/// Checks the limits an update of container `id` sets, as returned by
+/// `update_limits`, against the caps of the client and the host's
+/// overcommit ratios.
+///
+/// Fails with a `PermissionDenied` error listing every violation.
+pub async fn admit_update(
+    policy: &PolicyConfig,
+    docker: &Docker,
+    host: &HostLimits,
+    identity: &Identity,
+    tenant: Option<&TenantConfig>,
+    id: &str,
+    memory: Option<u64>,
+    cpus: Option<f64>,
+) -> Result<()> {
+    let update = &policy.update;
+    let mut violations = Vec::new();
+
+    if let Some(cap) = update.caps.iter().find(|cap| applies(cap, identity, tenant)) {
+        if let (Some(memory), Some(max)) = (memory, cap.max_memory) {
+            if memory == 0 || memory > max {
+                violations.push(format!("Memory limit must be at most {} bytes.", max));
+            }
+        }
+        if let (Some(cpus), Some(max)) = (cpus, cap.max_cpus) {
+            if cpus == 0.0 || cpus > max {
+                violations.push(format!("CPU limit must be at most {} CPUs.", max));
+            }
+        }
+    }
+
+    // Lifting a limit cannot be counted, caps are what stop it.
+    let memory_overcommit = update.memory_overcommit.filter(|_| memory.map_or(false, |m| m > 0));
+    let cpu_overcommit = update.cpu_overcommit.filter(|_| cpus.map_or(false, |c| c > 0.0));
+    if memory_overcommit.is_some() || cpu_overcommit.is_some() {
+        let (info, (used_memory, used_cpus)) = futures::try_join!(docker.info(), host.sum(docker, id))?;
+        let total_memory = used_memory + memory.unwrap_or(0);
+        let total_cpus = used_cpus + cpus.unwrap_or(0.0);
+
+        if let Some(ratio) = memory_overcommit {
+            if total_memory as f64 > info.mem_total as f64 * ratio {
+                violations.push(format!(
+                    "Memory limits would add up to {} bytes, over {} times the host's {} bytes.",
+                    total_memory, ratio, info.mem_total
+                ));
+            }
+        }
+        if let Some(ratio) = cpu_overcommit {
+            if total_cpus > info.ncpu as f64 * ratio {
+                violations.push(format!(
+                    "CPU limits would add up to {} CPUs, over {} times the host's {} CPUs.",
+                    total_cpus, ratio, info.ncpu
+                ));
+            }
+        }
+    }
+
+    reject(violations)
+}
+
+/// Memory and CPU limits of every container on the host by ID, so the
+/// overcommit check only inspects containers it has not seen before.
+/// Updates through the broker drop the entry of their container, limits
+/// changed with Docker directly are seen once the container is recreated.
+#[derive(Default)]
+pub struct HostLimits {
+    limits: Mutex<HashMap<String, (u64, f64)>>,
+}
+
+impl HostLimits {
+    /// Memory and CPU limits of every container but `except` added up.
+    async fn sum(&self, docker: &Docker, except: &str) -> Result<(u64, f64)> {
+        let options = ListContainersOptions::<String> {
+            all: true,
+            ..Default::default()
+        };
+        let containers = docker.list_containers(Some(options)).await?;
+
+        let ids: HashSet<&str> = containers.iter().map(|c| c.id.as_str()).collect();
+        let missing: Vec<&str> = {
+            let mut limits = self.limits.lock().unwrap();
+            // Removed containers go with the first scan that misses them.
+            limits.retain(|id, _| ids.contains(id.as_str()));
+            ids.iter()
+                .filter(|id| **id != except && !limits.contains_key(**id))
+                .copied()
+                .collect()
+        };
+        for id in missing {
+            let container = docker.inspect_container(id, None::<InspectContainerOptions>).await?;
+            let limits = limits(Some(&container.host_config));
+            self.limits.lock().unwrap().insert(id.to_owned(), limits);
+        }
+
+        let limits = self.limits.lock().unwrap();
+        Ok(limits
+            .iter()
+            .filter(|(id, _)| id.as_str() != except)
+            .fold((0, 0.0), |(memory, cpus), (_, (m, c))| (memory + m, cpus + c)))
+    }
+
+    /// Drops the limits of container `id`, after they changed.
+    pub fn forget(&self, id: &str) {
+        self.limits.lock().unwrap().remove(id);
+    }
+}
+
+/// Memory limit in bytes and CPU limit in CPUs of `host_config`, 0 for
+/// unlimited.
+pub fn limits(host_config: Option<&HostConfig<String>>) -> (u64, f64) {
+    let host_config = match host_config {
+        Some(host_config) => host_config,
+        None => return (0, 0.0),
+    };
+
+    let memory = host_config.memory.filter(|m| *m > 0).map_or(0, |m| m as u64);
+    let cpus = match (host_config.nano_cpus, host_config.cpu_quota) {
+        (Some(nano), _) if nano > 0 => nano as f64 / 1e9,
+        (_, Some(quota)) if quota > 0 => quota as f64 / cpu_period(None, host_config),
+        _ => 0.0,
+    };
+
+    (memory, cpus)
+}
+
+/// Memory and CPU limits a container configured with `current` has after
+/// `update`, `None` for those the update leaves alone.
+pub fn update_limits(current: &HostConfig<String>, update: &UpdateContainerOptions) -> (Option<u64>, Option<f64>) {
+    // Docker takes -1 and 0 alike for no limit.
+    let memory = update.memory.map(|m| m.max(0) as u64);
+    let cpus = match (update.nano_cpus, update.cpu_quota) {
+        (Some(nano), _) => Some(nano.max(0) as f64 / 1e9),
+        (None, Some(quota)) if quota > 0 => Some(quota as f64 / cpu_period(update.cpu_period, current)),
+        (None, Some(_)) => Some(0.0),
+        // A new period alone rescales the quota the container already has.
+        (None, None) => match current.cpu_quota {
+            Some(quota) if quota > 0 && update.cpu_period.is_some() => {
+                Some(quota as f64 / cpu_period(update.cpu_period, current))
+            }
+            _ => None,
+        },
+    };
+
+    (memory, cpus)
+}
+
+/// CPU period of `period` if set, else of `host_config`, in microseconds.
+fn cpu_period(period: Option<i64>, host_config: &HostConfig<String>) -> f64 {
+    period
+        .map(|p| p as f64)
+        .or_else(|| host_config.cpu_period.map(|p| p as f64))
+        .filter(|p| *p > 0.0)
+        .unwrap_or(DEFAULT_CPU_PERIOD)
+}
+
+fn applies(cap: &CapConfig, identity: &Identity, tenant: Option<&TenantConfig>) -> bool {
+    let member = match identity {
+        Identity::Key(name) => cap.keys.contains(name),
+        Identity::Token(name) => cap.tokens.contains(name),
+        Identity::Local { uid, .. } => cap.uids.contains(uid),
+        Identity::Address(_) => false,
+    };
+
+    member || (cap.tenant.is_some() && cap.tenant.as_ref() == tenant.map(|t| &t.name))
+}
+
+fn reject(violations: Vec<String>) -> Result<()> {
+    if !violations.is_empty() {
+        bail!(Error::new(
+            ErrorCode::PermissionDenied,
+            format!("Rejected by policy: {}", violations.join(" ")),
+        ));
+    }
+
+    Ok(())
+}
+
+fn apply_defaults(policy: &PolicyConfig, config: &mut Config<String>) {
+    let defaults = &policy.defaults;
+
+    if defaults.memory.is_some() || defaults.cpus.is_some() {
+        let host_config = config.host_config.get_or_insert_with(HostConfig::default);
+        if host_config.memory.filter(|m| *m > 0).is_none() {
+            host_config.memory = defaults.memory.map(|m| m as _);
+        }
+        let cpu_limited = host_config.nano_cpus.map_or(false, |n| n > 0)
+            || host_config.cpu_quota.map_or(false, |q| q > 0);
+        if !cpu_limited {
+            if let Some(cpus) = defaults.cpus {
+                host_config.nano_cpus = Some((cpus * 1e9) as _);
+            }
+        }
+    }
+
+    if !defaults.labels.is_empty() {
+        let labels = config.labels.get_or_insert_with(HashMap::new);
+        for (key, value) in &defaults.labels {
+            labels.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// `capability` as Docker compares it, upper case without `CAP_`.
+fn capability_name(capability: &str) -> String {
+    capability.to_ascii_uppercase().trim_start_matches("CAP_").to_owned()
+}
+
+/// Whether security option `option` lifts the confinement of a container.
+/// Docker takes both `=` and the older `:` as separator.
+fn unconfined(option: &str) -> bool {
+    let mut parts = option.splitn(2, |c| c == '=' || c == ':');
+    match (parts.next(), parts.next()) {
+        (Some("seccomp"), Some("unconfined"))
+        | (Some("apparmor"), Some("unconfined"))
+        | (Some("systempaths"), Some("unconfined"))
+        | (Some("label"), Some("disable")) => true,
+        _ => false,
+    }
+}
+
+/// Host paths `host_config` bind mounts, from `Binds` and `Mounts`.
+fn bind_sources(host_config: &HostConfig<String>) -> Vec<&str> {
+    let mut sources = Vec::new();
+
+    // `Binds` entries are `source:target[:options]`, sources that are not
+    // absolute paths name volumes.
+    for bind in host_config.binds.iter().flatten() {
+        let source = bind.split(':').next().unwrap_or("");
+        if source.starts_with('/') {
+            sources.push(source);
+        }
+    }
+    for mount in host_config.mounts.iter().flatten() {
+        match mount.type_.as_str() {
+            "bind" => sources.push(mount.source.as_str()),
+            // The local driver mounts `device` with the options in `o`,
+            // `o=bind` makes it a bind mount of any host path.
+            "volume" => {
+                let device = mount
+                    .volume_options
+                    .as_ref()
+                    .and_then(|options| options.driver_config.as_ref())
+                    .and_then(|driver| driver.options.get("device"));
+                if let Some(device) = device {
+                    sources.push(device.as_str());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    sources
+}
+
+/// Whether `path` is `allowed` or below it, both with their symlinks
+/// resolved on this host. Relative paths and paths with `..` never are.
+async fn path_within(path: &Path, allowed: &Path) -> bool {
+    path.is_absolute()
+        && !path.components().any(|c| c == Component::ParentDir)
+        && resolve(path).await.starts_with(resolve(allowed).await)
+}
+
+/// `path` with the symlinks of its longest existing prefix resolved. Docker
+/// creates missing bind sources, the missing rest is kept as it is.
+async fn resolve(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    loop {
+        if let Ok(resolved) = fs::canonicalize(existing).await {
+            return missing.iter().rev().fold(resolved, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return path.to_owned(),
+        }
+    }
+}
+
+/// Whether `image` matches `pattern`, both read as Docker reads them, so
+/// `nginx` and `docker.io/library/nginx` name the same image.
+///
+/// A pattern ending in `*` matches every repository below the path before
+/// it: `team/*` covers `team/app` but not `team-evil/app`. A pattern with a
+/// tag or digest matches only that one, any other every tag and digest of
+/// its repository.
+fn image_matches(pattern: &str, image: &str) -> bool {
+    let image = Reference::parse(image);
+    let name = image.name();
+
+    if pattern.ends_with('*') {
+        let prefix = pattern[..pattern.len() - 1].trim_end_matches('/');
+        if prefix.is_empty() {
+            return true;
+        }
+        // Two more components keep the parser from reading the prefix as
+        // an official image, it then only fills in the registry.
+        let prefix = Reference::parse(&format!("{}/_/_", prefix)).name();
+        let prefix = &prefix[..prefix.len() - "/_/_".len()];
+        return name.starts_with(prefix) && name[prefix.len()..].starts_with('/');
+    }
+
+    let pattern = Reference::parse(pattern);
+    // An image with neither tag nor digest is pulled as `latest`.
+    let tag = match (&image.tag, &image.digest) {
+        (None, None) => Some("latest".to_owned()),
+        (tag, _) => tag.clone(),
+    };
+
+    pattern.name() == name
+        && pattern.tag.as_ref().map_or(true, |t| Some(t) == tag.as_ref())
+        && pattern.digest.as_ref().map_or(true, |d| Some(d) == image.digest.as_ref())
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Context, Result};
+use bollard::auth::DockerCredentials;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_derive::Deserialize;
+use tracing::info;
+
+use crate::config::RegistryConfig;
+use crate::error::{Error, ErrorCode};
+
+/// Registry used by Docker for image references without a registry host.
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// Named set of credentials for one registry.
+///
+/// Clients refer to a profile by `name` and never see the secrets.
+#[derive(Clone, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub server: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub identity_token: Option<String>,
+}
+
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Profile")
+            .field("name", &self.name)
+            .field("server", &self.server)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct SealedProfiles {
+    profiles: Vec<Profile>,
+}
+
+/// Registry allowlist and credential profiles.
+#[derive(Debug, Default)]
+pub struct Registries {
+    allow: Vec<String>,
+    profiles: HashMap<String, Profile>,
+}
+
+impl Registries {
+    pub async fn load(config: &RegistryConfig) -> Result<Registries> {
+        let mut profiles = config.profiles.clone();
+
+        if let Some(path) = &config.credentials_file {
+            let key_path = config
+                .key_file
+                .as_ref()
+                .ok_or_else(|| anyhow!("registry.credentials_file requires registry.key_file."))?;
+            let key = read_key(key_path).await?;
+            let sealed = fs::read(path).await.context("Failed to read registry credentials.")?;
+
+            let plain = open(&key, &sealed)?;
+            let plain = String::from_utf8(plain).context("Registry credentials are not UTF-8.")?;
+            let sealed: SealedProfiles = toml::from_str(&plain).context("Failed to parse registry credentials.")?;
+            profiles.extend(sealed.profiles);
+        }
+
+        let mut registries = Registries {
+            allow: config.allow.iter().map(|r| normalize(r).to_owned()).collect(),
+            profiles: HashMap::new(),
+        };
+
+        for profile in profiles {
+            if registries.profiles.contains_key(&profile.name) {
+                bail!("Registry profile {} defined twice.", profile.name);
+            }
+            registries.profiles.insert(profile.name.clone(), profile);
+        }
+
+        info!("Loaded {} registry profiles.", registries.profiles.len());
+
+        Ok(registries)
+    }
+
+    /// Fails unless the registry `image` lives in is on the allowlist.
+    pub fn check_image(&self, image: &str) -> Result<()> {
+        let registry = registry_of(image);
+
+        if self.allow.iter().any(|r| r == registry) {
+            Ok(())
+        } else {
+            bail!(Error::new(
+                ErrorCode::PermissionDenied,
+                format!("Registry {} is not allowed.", registry),
+            ))
+        }
+    }
+
+    /// Credentials from profile `name`, which must belong to the registry of `image`.
+    pub fn credentials(&self, name: &str, image: &str) -> Result<DockerCredentials> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| Error::new(ErrorCode::NotFound, format!("Unknown registry profile {}.", name)))?;
+
+        if normalize(&profile.server) != registry_of(image) {
+            bail!(Error::new(
+                ErrorCode::PermissionDenied,
+                format!("Registry profile {} cannot be used for {}.", name, registry_of(image)),
+            ));
+        }
+
+        Ok(DockerCredentials {
+            username: profile.username.clone(),
+            password: profile.password.clone(),
+            identitytoken: profile.identity_token.clone(),
+            serveraddress: Some(profile.server.clone()),
+            ..Default::default()
+        })
+    }
+}
+
+/// An image reference split into its parts, as Docker reads it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    /// Registry host, `DEFAULT_REGISTRY` when the reference names none.
+    pub registry: String,
+    /// Repository path, with `library/` for official images on Docker Hub.
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    pub fn parse(image: &str) -> Reference {
+        let (name, digest) = match image.find('@') {
+            Some(i) => (&image[..i], Some(image[i + 1..].to_owned())),
+            None => (image, None),
+        };
+        // A colon after the last slash starts the tag, one before it is
+        // the port of the registry.
+        let (name, tag) = match name.rfind(':') {
+            Some(i) if !name[i..].contains('/') => (&name[..i], Some(name[i + 1..].to_owned())),
+            _ => (name, None),
+        };
+        let (registry, repository) = match name.find('/') {
+            Some(i) if is_host(&name[..i]) => (normalize(&name[..i]), &name[i + 1..]),
+            _ => (DEFAULT_REGISTRY, name),
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository.to_owned()
+        };
+
+        Reference {
+            registry: registry.to_owned(),
+            repository,
+            tag,
+            digest,
+        }
+    }
+
+    /// `registry/repository`, the same for every spelling of the image.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// Whether the reference picks a tag or digest itself.
+    pub fn is_pinned(&self) -> bool {
+        self.tag.is_some() || self.digest.is_some()
+    }
+}
+
+/// Returns the registry host an image reference points at.
+pub fn registry_of(image: &str) -> &str {
+    match image.find('/') {
+        Some(i) if is_host(&image[..i]) => normalize(&image[..i]),
+        _ => DEFAULT_REGISTRY,
+    }
+}
+
+/// Whether the first component of a reference names a registry rather
+/// than a Docker Hub namespace.
+fn is_host(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
+}
+
+fn normalize(registry: &str) -> &str {
+    match registry {
+        "index.docker.io" | "registry-1.docker.io" => DEFAULT_REGISTRY,
+        r => r,
+    }
+}
+
+/// Encrypts the plain TOML profiles at `input` and writes them next to it
+/// with a `.sealed` extension.
+pub async fn seal_file(key_path: &Path, input: &Path) -> Result<PathBuf> {
+    let key = read_key(key_path).await?;
+    let plain = fs::read(input).await.context("Failed to read registry credentials.")?;
+
+    let plain_str = std::str::from_utf8(&plain).context("Registry credentials are not UTF-8.")?;
+    toml::from_str::<SealedProfiles>(plain_str).context("Failed to parse registry credentials.")?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("Failed to generate nonce."))?;
+
+    let mut sealed = plain;
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+        .map_err(|_| anyhow!("Failed to encrypt registry credentials."))?;
+
+    let mut output = nonce.to_vec();
+    output.extend(sealed);
+
+    let output_path = input.with_extension("sealed");
+    fs::write(&output_path, &output).await.context("Failed to write sealed credentials.")?;
+
+    Ok(output_path)
+}
+
+fn open(key: &LessSafeKey, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        bail!("Registry credentials file is truncated.");
+    }
+
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("Registry credentials file is truncated."))?;
+
+    let mut in_out = sealed.to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| anyhow!("Failed to decrypt registry credentials."))?
+        .len();
+    in_out.truncate(len);
+
+    Ok(in_out)
+}
+
+async fn read_key(path: &Path) -> Result<LessSafeKey> {
+    let key = fs::read(path).await.context("Failed to read registry key.")?;
+
+    let key = match std::str::from_utf8(&key).map(str::trim) {
+        Ok(text) if text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            hex::decode(text).context("Registry key is not valid hex.")?
+        }
+        _ => key,
+    };
+
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow!("Registry key must be 32 bytes."))?;
+
+    Ok(LessSafeKey::new(key))
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 extern crate anyhow;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use tracing::info;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use broker_proto::Protocol;
 use bollard::Docker;
+use bytes::Bytes;
 
 use futures_util::stream::StreamExt;
 use futures_util::stream::TryStreamExt;
@@ -18,6 +23,38 @@ extern crate rmp_serde as rmps;
 
 #[allow(unused_imports)]
 use bollard::container::*;
+use bollard::image::{
+    BuildImageOptions, BuildImageResults, CreateImageOptions, CreateImageResults,
+    PushImageOptions, PushImageResults,
+};
+use bollard::system::EventsOptions;
+
+use crate::auth::Identity;
+use crate::capabilities;
+use crate::codec::{self, Accept, Format, Writer};
+use crate::config::TenantConfig;
+use crate::error::{self, Error, ErrorCode};
+use crate::policy;
+use crate::registry::{Reference, Registries};
+use crate::session::Session;
+use crate::state::State;
+use crate::tenant;
+use crate::tunnel;
+
+/// Largest request header accepted before the stream is rejected.
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Largest build context a client may upload after a `Build` header.
+pub const MAX_BUILD_CONTEXT: usize = 512 * 1024 * 1024;
+
+/// Size of the reads a build context is streamed to Docker in.
+const BUILD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Retry hint sent with requests refused for lack of a free slot.
+const BUSY_RETRY: Duration = Duration::from_millis(100);
+
+/// Deepest nesting of msgpack arrays and maps accepted in a request header.
+const MAX_HEADER_DEPTH: usize = 64;
 
 /*
 #[derive(Debug, Error)]
@@ -27,43 +64,142 @@ enum ProtocolError {
 }
 */
 
-pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
+/// Reads one request from `recv` and writes the response frames to `send`.
+///
+/// Every frame is a single msgpack encoded `Protocol`. Most commands answer
+/// with exactly one frame, streaming commands such as `Build` send progress
+/// frames first and finish with the final result.
+pub async fn handle_request<R, W>(state: &State, session: &Session, recv: &mut R, send: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut send = Writer::new(send, session.format);
 
-    let docker =  if let Ok(d) = Docker::connect_with_local_defaults() {
+    let Header { content, rest, accept, format } = match read_header(recv, session.format).await {
+        Ok(header) => header,
+        Err(e) => return write_frame(&mut send, &error::response(&e)).await,
+    };
+    send.set_format(format);
+    send.negotiate(accept, &state.config.compression, &state.metrics);
+
+    let docker =  if let Ok(d) = state.docker() {
         d
     } else {
-        let resp = Protocol::error_none("Docker not running.");
-
-        let mut output = Vec::new();
-        resp.serialize(&mut Serializer::new(&mut output))?;
+        let resp = Protocol::error(ErrorCode::RuntimeUnavailable, "Docker not running.", None);
 
-        return Ok(output);
+        return write_frame(&mut send, &resp).await;
     };
 
-    use std::convert::TryFrom;
-    let request: Protocol = if let Ok(req) = broker_proto::Protocol::try_from(&buf[..]) {
+    let request: Protocol = if let Ok(req) = decode(&content, format) {
         req
     } else {
-        let resp = Protocol::error_none("Invalid request format.");
+        let resp = Protocol::error(ErrorCode::InvalidArgument, "Invalid request format.", None);
+
+        return write_frame(&mut send, &resp).await;
+    };
+
+    // Clients predating versioned headers speak version 1.
+    if let Err(e) = capabilities::check_version(request.version.unwrap_or(1)) {
+        return write_frame(&mut send, &error::response(&e)).await;
+    }
+
+    if let broker_proto::Type::Command(cmd) = &request.packet_type {
+        let command = format!("{:?}", cmd.cmd_type);
+        if !session.access.allows(&cmd.cmd_type) {
+            info!(client = %session.identity, command = %command, "Command not allowed.");
+            let resp = Protocol::error(
+                ErrorCode::PermissionDenied,
+                &format!("{} is not allowed on this listener.", command),
+                None,
+            );
 
-        let mut output = Vec::new();
-        resp.serialize(&mut Serializer::new(&mut output))?;
+            return write_frame(&mut send, &resp).await;
+        }
+
+        if let Err(retry) = state.limiter.check(&session.identity, &command) {
+            info!(client = %session.identity, command = %command, "Rate limited.");
+            let e = Error::new(
+                ErrorCode::RateLimited,
+                format!("Rate limited, retry in {} ms.", retry.as_millis().max(1)),
+            );
+
+            return write_frame(&mut send, &error::response(&e.retry_after(retry).into())).await;
+        }
+    }
 
-        return Ok(output);
+    // Streams that last as long as the client wants draw on slots of their
+    // own, so they cannot starve one-off calls. Requests finding no free
+    // slot are refused rather than queued.
+    let _permit = match &request.packet_type {
+        broker_proto::Type::Command(cmd) => {
+            let (slots, kind) = if long_lived(&cmd.cmd_type, cmd.argument.as_ref()) {
+                (&state.streams, "streams")
+            } else {
+                (&state.operations, "operations")
+            };
+            match slots.try_acquire() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    info!(client = %session.identity, "Too many {} running.", kind);
+                    let e = Error::new(
+                        ErrorCode::RateLimited,
+                        format!("Too many {} running, retry in {} ms.", kind, BUSY_RETRY.as_millis()),
+                    );
+
+                    return write_frame(&mut send, &error::response(&e.retry_after(BUSY_RETRY).into())).await;
+                }
+            }
+        },
+        _ => None,
     };
 
+    // Hello stays open to everyone, so clients can find out what they may do.
+    let tenant = match (state.tenants.of(&session.identity), &request.packet_type) {
+        (Ok(tenant), _) => tenant,
+        (Err(_), broker_proto::Type::Command(cmd)) if matches!(cmd.cmd_type, broker_proto::CommandType::Hello) => None,
+        (Err(e), _) => {
+            info!(client = %session.identity, "Client in no tenant.");
+            return write_frame(&mut send, &error::response(&e)).await;
+        }
+    };
+    if let (Some(tenant), broker_proto::Type::Command(cmd)) = (tenant, &request.packet_type) {
+        if let Err(e) = tenant::check_command(tenant, &cmd.cmd_type) {
+            return write_frame(&mut send, &error::response(&e)).await;
+        }
+        if let Some(name) = target(cmd.argument.as_ref()) {
+            if let Err(e) = tenant::check_owner(&docker, tenant, name).await {
+                return write_frame(&mut send, &error::response(&e)).await;
+            }
+        }
+    }
+
     let resp = match request.packet_type {
-        broker_proto::Type::Transfer => Protocol::error_none("Not implemented."),
+        broker_proto::Type::Transfer => Protocol::error(ErrorCode::InvalidArgument, "Not implemented.", None),
         broker_proto::Type::Response => {
-            Protocol::error_none("Server cannot receive Response.")
+            Protocol::error(ErrorCode::InvalidArgument, "Server cannot receive Response.", None)
         },
         broker_proto::Type::Command(cmd) => {
             match cmd.cmd_type {
+                broker_proto::CommandType::Hello => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Hello{protocol} = arg {
+                            match capabilities::hello(state, session, &docker, protocol).await {
+                                Ok(res) => res,
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
                 broker_proto::CommandType::List => {
 
-                    match list_containers(&docker).await {
+                    match list_containers(&docker, tenant).await {
                         Ok(res) => res,
-                        Err(e) => Protocol::error_none(&e.to_string())
+                        Err(e) => error::response(&e)
                     }
                     
                 },
@@ -73,13 +209,13 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::ContainerChanges{name} = arg {
                             match get_container_changes(&docker, &name).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Container => {
@@ -87,27 +223,27 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::InspectContainer{name, options} = arg {
                             match inspect_container(&docker, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Stats => {
                     if let Some(arg) = cmd.argument {
                         if let broker_proto::Arguments::Stats{name, options} = arg {
-                            match get_stats(&docker, &name, options).await {
+                            match get_stats(&docker, &name, options, &mut send).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Top => {
@@ -115,41 +251,48 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::Top{name, options} = arg {
                             match container_top(&docker, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Log => {
                     if let Some(arg) = cmd.argument {
                         if let broker_proto::Arguments::Logs{name, options} = arg {
-                            match get_logs(&docker, &name, options).await {
+                            match get_logs(&docker, &name, options, &mut send).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Stop => {
                     if let Some(arg) = cmd.argument {
-                        if let broker_proto::Arguments::Stop{name, options} = arg {
-                            match stop_container(&docker, &name, options).await {
-                                Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
-                            }
-                        } else {
-                            Protocol::error_none("Invalid argument.")
+                        match arg {
+                            broker_proto::Arguments::Stop{name, options, dry_run} => {
+                                match stop_container(&docker, &name, options, dry_run).await {
+                                    Ok(res) => res,
+                                    Err(e) => error::response(&e)
+                                }
+                            },
+                            broker_proto::Arguments::StopMatching{filters, options, dry_run} => {
+                                match stop_matching(&docker, filters, options, tenant, dry_run).await {
+                                    Ok(res) => res,
+                                    Err(e) => error::response(&e)
+                                }
+                            },
+                            _ => Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None),
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Start => {
@@ -157,13 +300,13 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::Start{name, options} = arg {
                             match start_container(&docker, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Kill => {
@@ -171,13 +314,13 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::Kill{name, options} = arg {
                             match kill_container(&docker, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Restart => {
@@ -185,93 +328,475 @@ pub async fn handle_request(buf: Vec<u8>) -> Result<Vec<u8>> {
                         if let broker_proto::Arguments::Restart{name, options} = arg {
                             match restart_container(&docker, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Prune => {
                     if let Some(arg) = cmd.argument {
-                        if let broker_proto::Arguments::Prune{options} = arg {
-                            match prune_container(&docker, options).await {
+                        if let broker_proto::Arguments::Prune{options, dry_run} = arg {
+                            match prune_container(&docker, options, tenant, dry_run).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Remove => {
                     if let Some(arg) = cmd.argument {
-                        if let broker_proto::Arguments::Remove{name, options} = arg {
-                            match remove_container(&docker, &name, options).await {
+                        if let broker_proto::Arguments::Remove{name, options, dry_run} = arg {
+                            match remove_container(&docker, &name, options, dry_run).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Update => {
                     if let Some(arg) = cmd.argument {
                         if let broker_proto::Arguments::Update{name, options} = arg {
-                            match update_container(&docker, &name, options).await {
+                            match update_container(&docker, state, session, tenant, &name, options).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
                 broker_proto::CommandType::Create => {
                     if let Some(arg) = cmd.argument {
                         if let broker_proto::Arguments::Create{config, options} = arg {
-                            match create_container(&docker, config, options).await {
+                            match create_container(&docker, state, tenant, config, options).await {
+                                Ok(res) => res,
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
+                broker_proto::CommandType::Build => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Build{options} = arg {
+                            match build_image(&docker, state, session, options, rest, recv, &mut send).await {
+                                Ok(res) => res,
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
+                broker_proto::CommandType::Pull => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Pull{image, tag, profile} = arg {
+                            match pull_image(&docker, &state.registries, &image, tag, profile).await {
+                                Ok(res) => res,
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
+                broker_proto::CommandType::Push => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Push{name, tag, profile} = arg {
+                            match push_image(&docker, &state.registries, &name, tag, profile).await {
                                 Ok(res) => res,
-                                Err(e) => Protocol::error_none(&e.to_string())
+                                Err(e) => error::response(&e)
                             }
                         } else {
-                            Protocol::error_none("Invalid argument.")
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
                         }
                     } else {
-                        Protocol::error_none("No parameter received.")
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
                     }
                 },
-                _ => Protocol::error_none("Not implemented"),
+                broker_proto::CommandType::System => {
+                    match system_info(&docker).await {
+                        Ok(res) => res,
+                        Err(e) => error::response(&e)
+                    }
+                },
+                broker_proto::CommandType::Authenticate => {
+                    Protocol::error(ErrorCode::InvalidArgument, "Authenticate is only accepted on the first stream.", None)
+                },
+                broker_proto::CommandType::Events => {
+                    let options = match cmd.argument {
+                        Some(broker_proto::Arguments::Events{options}) => Some(options),
+                        None => Some(None),
+                        Some(_) => None,
+                    };
+                    if let Some(options) = options {
+                        match get_events(&docker, options, tenant, &mut send).await {
+                            Ok(res) => res,
+                            Err(e) => error::response(&e)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                    }
+                },
+                broker_proto::CommandType::Tunnel => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Tunnel{name, port} = arg {
+                            match tunnel::connect(&docker, &name, port).await {
+                                Ok(tcp) => {
+                                    write_frame(&mut send, &Protocol::response(&docker).await?).await?;
+                                    return tunnel::splice(tcp, rest, recv, &mut send).await;
+                                },
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
+                broker_proto::CommandType::Datagram => {
+                    if let Some(arg) = cmd.argument {
+                        if let broker_proto::Arguments::Datagram{name, port} = arg {
+                            match open_flow(&docker, session, &name, port).await {
+                                Ok((id, proto)) => {
+                                    write_frame(&mut send, &proto).await?;
+
+                                    // The flow lives until the client finishes its stream.
+                                    let _ = tokio::io::copy(recv, &mut tokio::io::sink()).await;
+                                    if let Some(flows) = &session.flows {
+                                        flows.close(id);
+                                    }
+                                    return Ok(());
+                                },
+                                Err(e) => error::response(&e)
+                            }
+                        } else {
+                            Protocol::error(ErrorCode::InvalidArgument, "Invalid argument.", None)
+                        }
+                    } else {
+                        Protocol::error(ErrorCode::InvalidArgument, "No parameter received.", None)
+                    }
+                },
+                _ => Protocol::error(ErrorCode::InvalidArgument, "Not implemented", None),
             }
         }
-        broker_proto::Type::Other => Protocol::error_none("Not implemented.")
+        broker_proto::Type::Other => Protocol::error(ErrorCode::InvalidArgument, "Not implemented.", None)
 
     };
 
     info!(content = %format!("{:#?}", &resp));
 
-    let mut output = Vec::new();
+    write_frame(&mut send, &resp).await
+}
+
+/// Reads the `Authenticate` request a client must send on its first stream
+/// when the server requires authentication, and answers it.
+///
+/// Returns the identity the connection is bound to from then on, or fails
+/// after answering with the reason it was refused.
+pub async fn authenticate<R, W>(state: &State, format: Format, recv: &mut R, send: &mut W) -> Result<Identity>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut send = Writer::new(send, format);
+
+    let result = async {
+        let Header { content, format, .. } = read_header(recv, format).await?;
+        send.set_format(format);
+
+        let credential = match decode(&content, format)?.packet_type {
+            broker_proto::Type::Command(cmd) => match (cmd.cmd_type, cmd.argument) {
+                (broker_proto::CommandType::Authenticate, Some(broker_proto::Arguments::Authenticate{credential})) => credential,
+                _ => bail!(Error::new(ErrorCode::PermissionDenied, "Authenticate before sending requests.")),
+            },
+            _ => bail!(Error::new(ErrorCode::PermissionDenied, "Authenticate before sending requests.")),
+        };
 
-    resp.serialize(&mut Serializer::new(&mut output))?;
+        state.keys.authenticate(&credential).await
+    }
+    .await;
 
-    Ok(output)
+    match result {
+        Ok(identity) => {
+            write_frame(&mut send, &Protocol::ok(broker_proto::Body::Identity(identity.to_string()))).await?;
+            Ok(identity)
+        }
+        Err(e) => {
+            write_frame(&mut send, &error::response(&e)).await?;
+            Err(e)
+        }
+    }
 }
 
-async fn list_containers(docker: &Docker) -> Result<Protocol> {
-    let containers = docker.list_containers(
-        Some(bollard::container::ListContainersOptions::<String>{
-            all: true,
-            ..Default::default()
-    })).await?;
+/// Container a command acts on by name, checked against the caller's tenant.
+fn target(argument: Option<&broker_proto::Arguments>) -> Option<&str> {
+    use broker_proto::Arguments;
+
+    match argument? {
+        Arguments::ContainerChanges{name}
+        | Arguments::InspectContainer{name, ..}
+        | Arguments::Stats{name, ..}
+        | Arguments::Top{name, ..}
+        | Arguments::Logs{name, ..}
+        | Arguments::Stop{name, ..}
+        | Arguments::Start{name, ..}
+        | Arguments::Kill{name, ..}
+        | Arguments::Restart{name, ..}
+        | Arguments::Remove{name, ..}
+        | Arguments::Update{name, ..}
+        | Arguments::Tunnel{name, ..}
+        | Arguments::Datagram{name, ..} => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether a command keeps its stream open until the client leaves:
+/// tunnels, datagram flows, event subscriptions, followed logs and
+/// streamed stats.
+fn long_lived(cmd_type: &broker_proto::CommandType, argument: Option<&broker_proto::Arguments>) -> bool {
+    use broker_proto::{Arguments, CommandType};
+
+    match (cmd_type, argument) {
+        (CommandType::Tunnel, _) | (CommandType::Datagram, _) | (CommandType::Events, _) => true,
+        (_, Some(Arguments::Logs{options: Some(options), ..})) => options.follow,
+        (_, Some(Arguments::Stats{options: Some(options), ..})) => options.stream,
+        _ => false,
+    }
+}
+
+/// Decodes a request header in `format`.
+///
+/// msgpack headers are scanned before they reach serde so that declared
+/// string, binary and collection lengths can never exceed the bytes
+/// actually sent.
+pub fn decode(buf: &[u8], format: Format) -> Result<Protocol> {
+    use std::convert::TryFrom;
+
+    let request = match format {
+        Format::Msgpack => {
+            match value_len(buf)? {
+                Some(len) if len == buf.len() => {}
+                _ => bail!(Error::new(ErrorCode::InvalidArgument, "Invalid request format.")),
+            }
+
+            Protocol::try_from(buf).ok()
+        }
+        Format::Json => serde_json::from_slice(buf).ok(),
+    };
+
+    request.ok_or_else(|| Error::new(ErrorCode::InvalidArgument, "Invalid request format.").into())
+}
+
+/// A request header as read off the stream.
+struct Header {
+    content: Vec<u8>,
+    /// Whatever the client sent after the header, which is where uploads
+    /// such as a build context begin.
+    rest: Vec<u8>,
+    /// Codecs the client accepts for responses.
+    accept: Accept,
+    format: Format,
+}
+
+/// Reads from `recv` until one complete header is buffered.
+///
+/// A msgpack header is one value, a JSON header one line. Connections
+/// negotiated for JSON always send JSON, otherwise a header starting with
+/// `{` selects it. A header wrapped in a `codec` envelope is unwrapped.
+async fn read_header<R: AsyncRead + Unpin>(recv: &mut R, format: Format) -> Result<Header> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let wrapped = buf.first() == Some(&codec::MARKER);
+        let json = !wrapped && (format == Format::Json || Format::of(&buf) == Format::Json);
+        let len = if wrapped {
+            codec::envelope_len(&buf, MAX_HEADER_SIZE)?
+        } else if json {
+            buf.iter().position(|b| *b == b'\n').map(|i| i + 1)
+        } else {
+            value_len(&buf)?
+        };
+
+        if let Some(len) = len {
+            let rest = buf.split_off(len);
+            let (accept, content) = if wrapped {
+                codec::open(&buf, MAX_HEADER_SIZE)?
+            } else {
+                (Accept::default(), buf)
+            };
+
+            return Ok(Header {
+                format: if format == Format::Json { format } else { Format::of(&content) },
+                content,
+                rest,
+                accept,
+            });
+        }
+
+        if buf.len() >= MAX_HEADER_SIZE + codec::ENVELOPE_HEADER {
+            bail!(Error::new(ErrorCode::InvalidArgument, "Request header too large."));
+        }
+
+        let n = recv
+            .read(&mut chunk)
+            .await
+            .map_err(|e| anyhow!("Failed reading request: {}", e))?;
+        if n == 0 {
+            // Scripts may finish the stream instead of ending the line.
+            if json && !buf.is_empty() {
+                buf.push(b'\n');
+                continue;
+            }
+            bail!(Error::new(ErrorCode::InvalidArgument, "Invalid request format."));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Length of the msgpack value at the start of `buf`, or `None` while it is
+/// still incomplete.
+///
+/// Walks the encoding without allocating. Values that could never fit in a
+/// header, nest deeper than `MAX_HEADER_DEPTH` or use the reserved marker are
+/// rejected here, before serde sees them.
+fn value_len(buf: &[u8]) -> Result<Option<usize>> {
+    let mut pos = 0;
+    // Values still to be read at each nesting level.
+    let mut pending: Vec<usize> = vec![1];
+
+    while let Some(left) = pending.last_mut() {
+        if *left == 0 {
+            pending.pop();
+            continue;
+        }
+        *left -= 1;
+
+        let marker = match buf.get(pos) {
+            Some(m) => *m,
+            None => return Ok(None),
+        };
+        pos += 1;
+
+        let mut size = |n: usize| -> Option<usize> {
+            let bytes = buf.get(pos..pos + n)?;
+            pos += n;
+            Some(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+        };
+
+        // Payload bytes to skip and child values to read.
+        let (skip, children) = match marker {
+            0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (Some(0), 0),
+            0x80..=0x8f => (Some(0), 2 * (marker & 0x0f) as usize),
+            0x90..=0x9f => (Some(0), (marker & 0x0f) as usize),
+            0xa0..=0xbf => (Some((marker & 0x1f) as usize), 0),
+            0xc4 | 0xd9 => (size(1), 0),
+            0xc5 | 0xda => (size(2), 0),
+            0xc6 | 0xdb => (size(4), 0),
+            0xc7 => (size(1).map(|n| n + 1), 0),
+            0xc8 => (size(2).map(|n| n + 1), 0),
+            0xc9 => (size(4).map(|n| n + 1), 0),
+            0xca => (Some(4), 0),
+            0xcb => (Some(8), 0),
+            0xcc | 0xd0 => (Some(1), 0),
+            0xcd | 0xd1 => (Some(2), 0),
+            0xce | 0xd2 => (Some(4), 0),
+            0xcf | 0xd3 => (Some(8), 0),
+            0xd4 => (Some(2), 0),
+            0xd5 => (Some(3), 0),
+            0xd6 => (Some(5), 0),
+            0xd7 => (Some(9), 0),
+            0xd8 => (Some(17), 0),
+            0xdc => match size(2) {
+                Some(n) => (Some(0), n),
+                None => return Ok(None),
+            },
+            0xdd => match size(4) {
+                Some(n) => (Some(0), n),
+                None => return Ok(None),
+            },
+            0xde => match size(2) {
+                Some(n) => (Some(0), 2 * n),
+                None => return Ok(None),
+            },
+            0xdf => match size(4) {
+                Some(n) => (Some(0), 2 * n),
+                None => return Ok(None),
+            },
+            _ => bail!(Error::new(ErrorCode::InvalidArgument, "Invalid request format.")),
+        };
+
+        let skip = match skip {
+            Some(skip) => skip,
+            None => return Ok(None),
+        };
+        // Every child takes at least one byte, so neither payloads nor
+        // collections can be larger than what is left of the header.
+        if skip > MAX_HEADER_SIZE - pos.min(MAX_HEADER_SIZE)
+            || children > MAX_HEADER_SIZE - pos.min(MAX_HEADER_SIZE)
+        {
+            bail!(Error::new(ErrorCode::InvalidArgument, "Request header too large."));
+        }
+        pos += skip;
+
+        if children > 0 {
+            if pending.len() >= MAX_HEADER_DEPTH {
+                bail!(Error::new(ErrorCode::InvalidArgument, "Request nested too deeply."));
+            }
+            pending.push(children);
+        }
+    }
+
+    if pos > buf.len() {
+        return Ok(None);
+    }
+
+    Ok(Some(pos))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(send: &mut Writer<'_, W>, resp: &Protocol) -> Result<()> {
+    let output = send.format().encode(resp)?;
+
+    send.write_frame(&output)
+        .await
+        .map_err(|e| anyhow!("Failed to send response: {}", e))?;
+
+    Ok(())
+}
+
+async fn list_containers(docker: &Docker, tenant: Option<&TenantConfig>) -> Result<Protocol> {
+    let mut options = bollard::container::ListContainersOptions::<String>{
+        all: true,
+        ..Default::default()
+    };
+    if let Some(tenant) = tenant {
+        tenant::scope(tenant, &mut options.filters);
+    }
+
+    let containers = docker.list_containers(Some(options)).await?;
 
   
 
@@ -303,7 +828,25 @@ async fn inspect_container(docker: &Docker, name: &str, opt: Option<InspectConta
     Ok(proto)
 }
 
-async fn get_stats(docker: &Docker, name: &str, opt: Option<StatsOptions>) -> Result<Protocol> {
+/// Streamed stats are sent as one frame per sample and end with an empty
+/// `Stats` once the container stops.
+async fn get_stats<W: AsyncWrite + Unpin>(docker: &Docker, name: &str, opt: Option<StatsOptions>, send: &mut Writer<'_, W>) -> Result<Protocol> {
+    if opt.as_ref().map_or(false, |o| o.stream) {
+        let progress = Protocol::response(&docker).await?;
+        let mut stream = docker.stats(name, opt);
+
+        while let Some(stats) = stream.next().await {
+            let mut proto = progress.clone();
+            proto.body = broker_proto::Body::Stats(vec![stats?]);
+            write_frame(send, &proto).await?;
+        }
+
+        let mut proto = Protocol::response(&docker).await?;
+        proto.body = broker_proto::Body::Stats(Vec::new());
+
+        return Ok(proto);
+    }
+
     let containers = docker
         .stats(name, opt)
         .take(1)
@@ -327,7 +870,25 @@ async fn container_top(docker: &Docker, name: &str, opt: Option<TopOptions<Strin
     Ok(proto)
 }
 
-async fn get_logs(docker: &Docker, name: &str, opt: Option<LogsOptions>) -> Result<Protocol> {
+/// Followed logs are sent as one frame per chunk as they arrive and end
+/// with an empty `LogOutput` once the container stops.
+async fn get_logs<W: AsyncWrite + Unpin>(docker: &Docker, name: &str, opt: Option<LogsOptions>, send: &mut Writer<'_, W>) -> Result<Protocol> {
+    if opt.as_ref().map_or(false, |o| o.follow) {
+        let progress = Protocol::response(&docker).await?;
+        let mut stream = docker.logs(name, opt);
+
+        while let Some(line) = stream.next().await {
+            let mut proto = progress.clone();
+            proto.body = broker_proto::Body::LogOutput(vec![line?]);
+            write_frame(send, &proto).await?;
+        }
+
+        let mut proto = Protocol::response(&docker).await?;
+        proto.body = broker_proto::Body::LogOutput(Vec::new());
+
+        return Ok(proto);
+    }
+
     let containers = docker
         .logs(name, opt)
         //.take(1)
@@ -340,13 +901,79 @@ async fn get_logs(docker: &Docker, name: &str, opt: Option<LogsOptions>) -> Resu
     Ok(proto)
 }
 
-async fn stop_container(docker: &Docker, name: &str, opt: Option<StopContainerOptions>) -> Result<Protocol> {
+/// Daemon events, one frame per event as they happen. The stream only ends
+/// once `until` passes, with an empty `Events`. Clients of a tenant only see
+/// events of its containers.
+async fn get_events<W: AsyncWrite + Unpin>(docker: &Docker, mut opt: Option<EventsOptions<String>>, tenant: Option<&TenantConfig>, send: &mut Writer<'_, W>) -> Result<Protocol> {
+    if let Some(tenant) = tenant {
+        tenant::scope(tenant, &mut opt.get_or_insert_with(Default::default).filters);
+    }
+
+    let progress = Protocol::response(&docker).await?;
+    let mut stream = docker.events(opt);
+
+    while let Some(event) = stream.next().await {
+        let mut proto = progress.clone();
+        proto.body = broker_proto::Body::Events(vec![event?]);
+        write_frame(send, &proto).await?;
+    }
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::Events(Vec::new());
+
+    Ok(proto)
+}
+
+async fn stop_container(docker: &Docker, name: &str, opt: Option<StopContainerOptions>, dry_run: bool) -> Result<Protocol> {
+    if dry_run {
+        let container = docker.inspect_container(name, None::<InspectContainerOptions>).await?;
+        let containers = if container.state.running {
+            containers_by_id(docker, &container.id, false).await?
+        } else {
+            Vec::new()
+        };
+
+        return dry_run_result(docker, containers, 0).await;
+    }
+
     docker
         .stop_container(name, opt).await?;
 
     Ok(Protocol::response(&docker).await?)
 }
 
+/// Stops every running container matching `filters`, of the caller's
+/// tenant only. Answers with the containers stopped, or with a dry run
+/// those that would be. Empty filters are refused rather than taken to
+/// mean every container.
+async fn stop_matching(docker: &Docker, mut filters: HashMap<String, Vec<String>>, opt: Option<StopContainerOptions>, tenant: Option<&TenantConfig>, dry_run: bool) -> Result<Protocol> {
+    if filters.values().all(Vec::is_empty) {
+        bail!(Error::new(ErrorCode::InvalidArgument, "Stopping by filters needs at least one filter."));
+    }
+    if let Some(tenant) = tenant {
+        tenant::scope(tenant, &mut filters);
+    }
+
+    let options = ListContainersOptions {
+        filters,
+        ..Default::default()
+    };
+    let containers = docker.list_containers(Some(options)).await?;
+    if dry_run {
+        return dry_run_result(docker, containers, 0).await;
+    }
+
+    for container in &containers {
+        docker
+            .stop_container(&container.id, opt).await?;
+    }
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::ContainerList(containers);
+
+    Ok(proto)
+}
+
 async fn start_container(docker: &Docker, name: &str, opt: Option<StartContainerOptions<String>>) -> Result<Protocol> {
     docker
         .start_container(name, opt).await?;
@@ -368,7 +995,18 @@ async fn restart_container(docker: &Docker, name: &str, opt: Option<RestartConta
     Ok(Protocol::response(&docker).await?)
 }
 
-async fn prune_container(docker: &Docker, opt: Option<PruneContainersOptions<String>>) -> Result<Protocol> {
+async fn prune_container(docker: &Docker, mut opt: Option<PruneContainersOptions<String>>, tenant: Option<&TenantConfig>, dry_run: bool) -> Result<Protocol> {
+    if let Some(tenant) = tenant {
+        tenant::scope(tenant, &mut opt.get_or_insert_with(Default::default).filters);
+    }
+
+    if dry_run {
+        let filters = opt.map(|o| o.filters).unwrap_or_default();
+        let containers = prunable(docker, filters).await?;
+        let space_reclaimed = reclaimed(&containers);
+        return dry_run_result(docker, containers, space_reclaimed).await;
+    }
+
     let res = docker.prune_containers(opt)
     .await?;
 
@@ -378,25 +1016,451 @@ async fn prune_container(docker: &Docker, opt: Option<PruneContainersOptions<Str
     Ok(proto)
 }
 
-async fn remove_container(docker: &Docker, name: &str, opt: Option<RemoveContainerOptions>) -> Result<Protocol> {
+async fn remove_container(docker: &Docker, name: &str, opt: Option<RemoveContainerOptions>, dry_run: bool) -> Result<Protocol> {
+    if dry_run {
+        let container = docker.inspect_container(name, None::<InspectContainerOptions>).await?;
+        if container.state.running && !opt.map_or(false, |o| o.force) {
+            bail!(Error::new(
+                ErrorCode::Conflict,
+                format!("Container {} is running, stop it first or force the removal.", name),
+            ));
+        }
+
+        let containers = containers_by_id(docker, &container.id, true).await?;
+        let space_reclaimed = reclaimed(&containers);
+        return dry_run_result(docker, containers, space_reclaimed).await;
+    }
+
     docker
         .remove_container(name, opt).await?;
 
     Ok(Protocol::response(&docker).await?)
 }
 
-async fn update_container(docker: &Docker, name: &str, opt: UpdateContainerOptions) -> Result<Protocol> {
+/// Stopped containers a prune with `filters` would remove, as Docker selects
+/// them: created, exited or dead, with the labels in `label`, without those
+/// in `label!` and created before `until`.
+async fn prunable(docker: &Docker, filters: HashMap<String, Vec<String>>) -> Result<Vec<APIContainers>> {
+    let mut list_filters = HashMap::new();
+    list_filters.insert("status".to_owned(), vec!["created".to_owned(), "exited".to_owned(), "dead".to_owned()]);
+    let mut without = Vec::new();
+    let mut until = None;
+
+    for (key, values) in filters {
+        match key.as_str() {
+            "label" => list_filters.entry("label".to_owned()).or_insert_with(Vec::new).extend(values),
+            "label!" => without.extend(values),
+            "until" if values.len() == 1 => until = Some(parse_until(&values[0])?),
+            "until" => bail!(Error::new(ErrorCode::InvalidArgument, "Only one until filter is allowed.")),
+            _ => bail!(Error::new(ErrorCode::InvalidArgument, format!("Invalid filter {}.", key))),
+        }
+    }
+
+    let containers = docker.list_containers(Some(ListContainersOptions::<String>{
+        all: true,
+        size: true,
+        filters: list_filters,
+        ..Default::default()
+    })).await?;
+
+    Ok(containers
+        .into_iter()
+        .filter(|c| until.map_or(true, |until| (c.created as f64) < until))
+        .filter(|c| !without.iter().any(|label| has_label(&c.labels, label)))
+        .collect())
+}
+
+/// Seconds since the Unix epoch `until` stands for, given as Docker takes it:
+/// a Unix timestamp, an RFC 3339 timestamp or a Go duration before now such
+/// as `1h30m`.
+fn parse_until(until: &str) -> Result<f64> {
+    if let Ok(timestamp) = until.parse::<f64>() {
+        return Ok(timestamp);
+    }
+    if let Some(timestamp) = parse_rfc3339(until) {
+        return Ok(timestamp);
+    }
+
+    let ago = parse_duration(until).ok_or_else(|| Error::new(
+        ErrorCode::InvalidArgument,
+        format!("Invalid until {}, use a timestamp or a duration such as 24h.", until),
+    ))?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    Ok(now - ago)
+}
+
+/// Seconds since the Unix epoch of an RFC 3339 timestamp such as
+/// `2020-05-18T11:06:40.5+02:00`. Date and time may be separated by `T`,
+/// `t` or a space, and `Z` may be lower case, as RFC 3339 allows.
+fn parse_rfc3339(timestamp: &str) -> Option<f64> {
+    if !timestamp.is_ascii() || timestamp.len() < 20 {
+        return None;
+    }
+    let (date, time) = (&timestamp[..10], &timestamp[10..]);
+    if &date[4..5] != "-" || &date[7..8] != "-" || !matches!(&time[..1], "T" | "t" | " ") || &time[3..4] != ":" || &time[6..7] != ":" {
+        return None;
+    }
+
+    let year = digits(&date[..4])?;
+    let month = digits(&date[5..7])?;
+    let day = digits(&date[8..10])?;
+    let hour = digits(&time[1..3])?;
+    let minute = digits(&time[4..6])?;
+    let second = digits(&time[7..9])?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if month < 1 || month > 12 || day < 1 || day > month_days[month as usize - 1] || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let mut rest = &time[9..];
+    let mut fraction = 0.0;
+    if rest.starts_with('.') {
+        let len = rest[1..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - 1);
+        if len == 0 {
+            return None;
+        }
+        fraction = rest[..=len].parse::<f64>().ok()?;
+        rest = &rest[len + 1..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && &rest[3..4] == ":" => {
+            let minutes = digits(&rest[1..3])? * 60 + digits(&rest[4..6])?;
+            match &rest[..1] {
+                "+" => minutes * 60,
+                "-" => -minutes * 60,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    Some(seconds as f64 + fraction)
+}
+
+/// Value of `s` if it is made of ASCII digits only.
+fn digits(s: &str) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Counts years from March so the leap day ends each one.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Seconds in a Go duration such as `1h30m` or `-1.5s`.
+fn parse_duration(duration: &str) -> Option<f64> {
+    let (sign, mut rest) = match duration.chars().next() {
+        Some('-') => (-1.0, &duration[1..]),
+        Some('+') => (1.0, &duration[1..]),
+        _ => (1.0, duration),
+    };
+    if rest == "0" {
+        return Some(0.0);
+    }
+    if rest.is_empty() {
+        return None;
+    }
+
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let amount = rest[..len].parse::<f64>().ok()?;
+        rest = &rest[len..];
+
+        let len = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len());
+        let unit = match &rest[..len] {
+            "ns" => 1e-9,
+            "us" | "µs" | "μs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 60.0 * 60.0,
+            _ => return None,
+        };
+        rest = &rest[len..];
+        seconds += amount * unit;
+    }
+
+    Some(sign * seconds)
+}
+
+/// Whether `labels` has `label`, a key or a `key=value` pair.
+fn has_label(labels: &HashMap<String, String>, label: &str) -> bool {
+    let mut parts = label.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => labels.get(key).map_or(false, |v| v == value),
+        (Some(key), None) => labels.contains_key(key),
+        _ => false,
+    }
+}
+
+async fn containers_by_id(docker: &Docker, id: &str, size: bool) -> Result<Vec<APIContainers>> {
+    let mut filters = HashMap::new();
+    filters.insert("id".to_owned(), vec![id.to_owned()]);
+
+    Ok(docker.list_containers(Some(ListContainersOptions::<String>{
+        all: true,
+        size,
+        filters,
+        ..Default::default()
+    })).await?)
+}
+
+/// Space removing `containers` would reclaim, their writable layers as
+/// listed with `size`. Volumes are not counted.
+fn reclaimed(containers: &[APIContainers]) -> u64 {
+    containers
+        .iter()
+        .filter_map(|c| c.size_rw)
+        .map(|size| size.max(0) as u64)
+        .sum()
+}
+
+/// Answers a dry run with the `containers` the command would act on.
+async fn dry_run_result(docker: &Docker, containers: Vec<APIContainers>, space_reclaimed: u64) -> Result<Protocol> {
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::DryRun{containers, space_reclaimed};
+
+    Ok(proto)
+}
+
+/// Updates are checked against the update policy and, for clients of a
+/// tenant, its quotas.
+async fn update_container(docker: &Docker, state: &State, session: &Session, tenant: Option<&TenantConfig>, name: &str, opt: UpdateContainerOptions) -> Result<Protocol> {
+    let _admitting = state.admitting.lock().await;
+
+    let current = docker.inspect_container(name, None::<InspectContainerOptions>).await?;
+    let (memory, cpus) = policy::update_limits(&current.host_config, &opt);
+    policy::admit_update(&state.config.policy, docker, &state.host_limits, &session.identity, tenant, &current.id, memory, cpus).await?;
+
+    if let Some(tenant) = tenant {
+        state.tenants.admit_update(docker, tenant, &current.id, memory, cpus).await?;
+    }
+
     docker
         .update_container(name, opt).await?;
+    state.host_limits.forget(&current.id);
 
     Ok(Protocol::response(&docker).await?)
 }
 
-async fn create_container(docker: &Docker, config: Config<String>, opt: Option<CreateContainerOptions<String>>) -> Result<Protocol> {
+/// Configs are checked against the admission policy first. Containers
+/// created by clients of a tenant are then labelled with it and count
+/// against its quotas.
+async fn create_container(docker: &Docker, state: &State, tenant: Option<&TenantConfig>, mut config: Config<String>, opt: Option<CreateContainerOptions<String>>) -> Result<Protocol> {
+    policy::admit(&state.config.policy, &state.registries, &mut config).await?;
+
+    let _admitting = state.admitting.lock().await;
+    if let Some(tenant) = tenant {
+        state.tenants.admit(docker, tenant, &mut config).await?;
+    }
 
     let res = docker.create_container(opt, config).await?;
     let mut proto = Protocol::response(&docker).await?;
     proto.body = broker_proto::Body::CreateContainerResults(res);
 
     Ok(proto)
-}
\ No newline at end of file
+}
+
+async fn system_info(docker: &Docker) -> Result<Protocol> {
+    let (version, info, disk_usage) = futures::try_join!(
+        docker.version(),
+        docker.info(),
+        docker.df(),
+    )?;
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::System{version, info, disk_usage};
+
+    Ok(proto)
+}
+
+async fn open_flow(docker: &Docker, session: &Session, name: &str, port: u16) -> Result<(u32, Protocol)> {
+    let flows = session
+        .flows
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorCode::InvalidArgument, "Datagrams are not supported on this transport."))?;
+
+    let addr = tunnel::resolve(docker, name, port).await?;
+    let id = flows.open(addr).await?;
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::Flow(id);
+
+    Ok((id, proto))
+}
+
+/// Streams the build context from `recv` to Docker as it arrives, so
+/// uploads are never held in memory whole.
+async fn build_image<R, W>(docker: &Docker, state: &State, session: &Session, mut options: BuildImageOptions<String>, mut context: Vec<u8>, recv: &mut R, send: &mut Writer<'_, W>) -> Result<Protocol>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    policy::admit_build(&state.config.policy, &state.registries, &session.identity, &mut options)?;
+
+    if context.is_empty() {
+        context.resize(BUILD_CHUNK_SIZE, 0);
+        let n = recv
+            .read(&mut context)
+            .await
+            .map_err(|e| anyhow!("Failed reading build context: {}", e))?;
+        if n == 0 {
+            bail!(Error::new(ErrorCode::InvalidArgument, "No build context received."));
+        }
+        context.truncate(n);
+    }
+
+    let (mut tar, body) = hyper::Body::channel();
+    let upload = async move {
+        let mut size = context.len();
+        let mut chunk = Bytes::from(context);
+        let mut buf = vec![0; BUILD_CHUNK_SIZE];
+        loop {
+            if size > MAX_BUILD_CONTEXT {
+                tar.abort();
+                bail!(Error::new(
+                    ErrorCode::InvalidArgument,
+                    format!("Build context larger than {} bytes.", MAX_BUILD_CONTEXT),
+                ));
+            }
+            if tar.send_data(chunk).await.is_err() {
+                // Docker stopped reading, the build output says why.
+                return Ok(());
+            }
+
+            let n = recv
+                .read(&mut buf)
+                .await
+                .map_err(|e| anyhow!("Failed reading build context: {}", e))?;
+            if n == 0 {
+                return Ok(());
+            }
+            size += n;
+            chunk = Bytes::copy_from_slice(&buf[..n]);
+        }
+    };
+
+    let output = async {
+        let progress = Protocol::response(&docker).await?;
+        let mut stream = docker.build_image(options, None, Some(body));
+        let mut image_id = None;
+
+        while let Some(res) = stream.next().await {
+            let lines = match res? {
+                BuildImageResults::BuildImageStream{stream} => stream,
+                BuildImageResults::BuildImageStatus{status, ..} => status,
+                BuildImageResults::BuildImageAux{aux} => {
+                    image_id = Some(aux.id);
+                    continue;
+                },
+                BuildImageResults::BuildImageError{error, ..} => bail!("{}", error.trim_end()),
+            };
+
+            for line in lines.lines().filter(|l| !l.trim().is_empty()) {
+                let mut proto = progress.clone();
+                proto.body = broker_proto::Body::BuildOutput(line.to_owned());
+                write_frame(send, &proto).await?;
+            }
+        }
+
+        Ok(image_id)
+    };
+
+    let ((), image_id) = futures::try_join!(upload, output)?;
+    let image_id = image_id.ok_or_else(|| anyhow!("Build finished without an image ID."))?;
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::BuildResult(image_id);
+
+    Ok(proto)
+}
+
+async fn pull_image(docker: &Docker, registries: &Registries, image: &str, tag: Option<String>, profile: Option<String>) -> Result<Protocol> {
+    registries.check_image(image)?;
+    let credentials = match profile {
+        Some(profile) => Some(registries.credentials(&profile, image)?),
+        None => None,
+    };
+
+    let options = CreateImageOptions {
+        from_image: image.to_owned(),
+        tag: tag_of(image, tag)?,
+        ..Default::default()
+    };
+
+    let res = docker
+        .create_image(Some(options), None, credentials)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    for r in &res {
+        if let CreateImageResults::CreateImageError{error, ..} = r {
+            bail!("{}", error);
+        }
+    }
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::CreateImageResults(res);
+
+    Ok(proto)
+}
+
+async fn push_image(docker: &Docker, registries: &Registries, name: &str, tag: Option<String>, profile: Option<String>) -> Result<Protocol> {
+    registries.check_image(name)?;
+    let credentials = match profile {
+        Some(profile) => Some(registries.credentials(&profile, name)?),
+        None => None,
+    };
+
+    let options = PushImageOptions {
+        tag: tag_of(name, tag)?,
+    };
+
+    let res = docker
+        .push_image(name, Some(options), credentials)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    for r in &res {
+        if let PushImageResults::PushImageError{error, ..} = r {
+            bail!("{}", error);
+        }
+    }
+
+    let mut proto = Protocol::response(&docker).await?;
+    proto.body = broker_proto::Body::PushImageResults(res);
+
+    Ok(proto)
+}
+
+/// Tag to send Docker next to `image`. References that carry their own tag
+/// or digest get none, Docker takes it from the reference, the others
+/// `latest` unless the client picked one.
+fn tag_of(image: &str, tag: Option<String>) -> Result<String> {
+    match (tag, Reference::parse(image).is_pinned()) {
+        (Some(_), true) => bail!(Error::new(
+            ErrorCode::InvalidArgument,
+            format!("Image {} already names a tag or digest.", image),
+        )),
+        (Some(tag), false) => Ok(tag),
+        (None, true) => Ok(String::new()),
+        (None, false) => Ok("latest".to_owned()),
+    }
+}
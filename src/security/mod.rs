@@ -6,22 +6,62 @@ use std::{
 use tokio::fs;
 
 extern crate anyhow;
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use tracing::info;
 
 pub async fn init_security(key: &Option<PathBuf>, cert: &Option<PathBuf>) -> Result<(quinn::PrivateKey, quinn::CertificateChain)> {
+    let (key, cert_chain) = load_der(key, cert).await?;
+
+    let key = quinn::PrivateKey::from_der(&key)?;
+    let cert_chain = cert_chain
+        .iter()
+        .map(|cert| quinn::Certificate::from_der(cert))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    Ok((key, quinn::CertificateChain::from_certs(cert_chain)))
+}
+
+/// TLS configuration for the TCP listeners, with the same certificate as
+/// the QUIC endpoint.
+pub async fn tls_config(key: &Option<PathBuf>, cert: &Option<PathBuf>, protocols: &[&[u8]]) -> Result<rustls::ServerConfig> {
+    let (key, cert_chain) = load_der(key, cert).await?;
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config.set_single_cert(
+        cert_chain.into_iter().map(rustls::Certificate).collect(),
+        rustls::PrivateKey(key),
+    )?;
+    config.set_protocols(&protocols.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+
+    Ok(config)
+}
+
+/// Private key and certificate chain in DER, read from `key` and `cert` or,
+/// without them, the self-signed pair generated on first use.
+pub async fn load_der(key: &Option<PathBuf>, cert: &Option<PathBuf>) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
     if let (Some(key_path), Some(cert_path)) = (&key, &cert) {
         let key = fs::read(key_path).await.context("Failed to read private key.")?;
         let key = if key_path.extension().map_or(false, |x| x == "der") {
-            quinn::PrivateKey::from_der(&key)?
+            key
         } else {
-            quinn::PrivateKey::from_pem(&key)?
+            let pkcs8 = rustls::internal::pemfile::pkcs8_private_keys(&mut &key[..])
+                .map_err(|_| anyhow!("Malformed PKCS #8 private key."))?;
+            let rsa = rustls::internal::pemfile::rsa_private_keys(&mut &key[..])
+                .map_err(|_| anyhow!("Malformed PKCS #1 private key."))?;
+            match pkcs8.into_iter().chain(rsa).next() {
+                Some(key) => key.0,
+                None => bail!("No private key found in {}.", key_path.display()),
+            }
         };
         let cert_chain = fs::read(cert_path).await.context("Failed to read certificate chain.")?;
         let cert_chain = if cert_path.extension().map_or(false, |x| x == "der") {
-            quinn::CertificateChain::from_certs(quinn::Certificate::from_der(&cert_chain))
+            vec![cert_chain]
         } else {
-            quinn::CertificateChain::from_pem(&cert_chain)?
+            rustls::internal::pemfile::certs(&mut &cert_chain[..])
+                .map_err(|_| anyhow!("Malformed certificate chain."))?
+                .into_iter()
+                .map(|cert| cert.0)
+                .collect()
         };
 
         Ok((key, cert_chain))
@@ -49,9 +89,6 @@ pub async fn init_security(key: &Option<PathBuf>, cert: &Option<PathBuf>) -> Res
             }
         };
 
-        let key = quinn::PrivateKey::from_der(&key)?;
-        let cert = quinn::Certificate::from_der(&cert)?;
-
-        Ok((key, quinn::CertificateChain::from_certs(vec![cert])))
+        Ok((key, vec![cert]))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,386 @@
+#[allow(unused_imports)]
+use std::{
+    ascii, io,
+    net::SocketAddr,
+    path::{self, Path, PathBuf},
+    str,
+    sync::Arc,
+};
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Result};
+use futures::{StreamExt, TryFutureExt};
+use structopt::{self, StructOpt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{error, info, info_span};
+use tracing_futures::Instrument as _;
+
+use crate::access::Access;
+use crate::auth;
+use crate::capabilities;
+use crate::codec::Format;
+use crate::config::{self, ListenerConfig};
+use crate::gateway;
+use crate::mux;
+use crate::registry;
+use crate::request;
+use crate::security;
+use crate::session::Session;
+use crate::state::State;
+use crate::tunnel::datagram::Flows;
+
+pub mod tls;
+pub mod unix;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "server")]
+pub struct Opt {
+
+    #[structopt(long = "keylog")]
+    pub keylog: bool,
+
+    #[structopt(parse(from_os_str), short = "k", long = "key", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    #[structopt(parse(from_os_str), short = "c", long = "cert", requires = "key")]
+    pub cert: Option<PathBuf>,
+
+    #[structopt(long = "stateless-retry")]
+    pub stateless_retry: bool,
+
+    #[structopt(long = "listen", default_value = "0.0.0.0:8000")]
+    pub listen: SocketAddr,
+
+    /// Also accept TLS over TCP on this address, for clients without UDP.
+    #[structopt(long = "listen-tls")]
+    pub listen_tls: Option<SocketAddr>,
+
+    /// Also accept WebSocket clients over TLS on this address, see
+    /// `[[gateway.tokens]]`.
+    #[structopt(long = "listen-ws")]
+    pub listen_ws: Option<SocketAddr>,
+
+    /// Also accept on-host clients on this Unix socket, see `[local]`.
+    #[structopt(parse(from_os_str), long = "listen-unix")]
+    pub listen_unix: Option<PathBuf>,
+
+    /// Serve only read-only commands on the listeners given here, those in
+    /// `[[listeners]]` set their own.
+    #[structopt(long = "read-only")]
+    pub read_only: bool,
+
+    #[structopt(parse(from_os_str), long = "config")]
+    pub config: Option<PathBuf>,
+
+    /// Encrypt a registry credentials file with `registry.key_file` and exit.
+    #[structopt(parse(from_os_str), long = "seal")]
+    pub seal: Option<PathBuf>,
+
+    /// Print a token for this name signed with `auth.token_key_file` and exit.
+    #[structopt(long = "issue-token")]
+    pub issue_token: Option<String>,
+}
+
+pub async fn run(options: Opt) -> Result<()> {
+    let config = config::Config::load(&options.config).await?;
+
+    if let Some(input) = &options.seal {
+        let key_path = config
+            .registry
+            .key_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("--seal requires registry.key_file in the config."))?;
+        let output = registry::seal_file(key_path, input).await?;
+        info!("Sealed credentials written to {}.", output.display());
+        return Ok(());
+    }
+
+    if let Some(name) = &options.issue_token {
+        let keys = auth::KeyStore::load(&config.auth).await?;
+        println!("{}", keys.issue(name)?);
+        return Ok(());
+    }
+
+    let state = Arc::new(State::new(config).await?);
+    let access = Access::new(options.read_only, None)?;
+
+    let metrics = state.clone();
+    tokio::spawn(async move { metrics.metrics.report().await });
+
+    let (_, incoming) = bind(&options, &state, &options.listen).await?;
+
+    if let Some(listen) = &options.listen_tls {
+        let (_, listener, acceptor) = tls::bind(&options, listen).await?;
+        tokio::spawn(tls::serve(state.clone(), listener, acceptor, access.clone()));
+    }
+
+    if let Some(listen) = &options.listen_ws {
+        let (_, listener, acceptor) = gateway::ws::bind(&options, listen).await?;
+        tokio::spawn(gateway::ws::serve(state.clone(), listener, acceptor, access.clone()));
+    }
+
+    if let Some(path) = &options.listen_unix {
+        let listener = unix::bind(&state, path).await?;
+        tokio::spawn(unix::serve(state.clone(), listener, access.clone()));
+    }
+
+    for listener in &state.config.listeners {
+        listen(&options, &state, listener).await?;
+    }
+
+    serve(state, incoming, access).await
+}
+
+/// Binds a listener from `[[listeners]]` and serves it in the background.
+///
+/// Returns the address it listens on, `None` for a Unix socket.
+pub async fn listen(options: &Opt, state: &Arc<State>, listener: &ListenerConfig) -> Result<Option<SocketAddr>> {
+    let access = Access::new(listener.read_only, listener.commands.as_deref())?;
+
+    match (&listener.quic, &listener.tls, &listener.unix, &listener.websocket) {
+        (Some(listen), None, None, None) => {
+            let (addr, incoming) = bind(options, state, listen).await?;
+            tokio::spawn(serve(state.clone(), incoming, access));
+            Ok(Some(addr))
+        }
+        (None, Some(listen), None, None) => {
+            let (addr, listener, acceptor) = tls::bind(options, listen).await?;
+            tokio::spawn(tls::serve(state.clone(), listener, acceptor, access));
+            Ok(Some(addr))
+        }
+        (None, None, Some(path), None) => {
+            let listener = unix::bind(state, path).await?;
+            tokio::spawn(unix::serve(state.clone(), listener, access));
+            Ok(None)
+        }
+        (None, None, None, Some(listen)) => {
+            let (addr, listener, acceptor) = gateway::ws::bind(options, listen).await?;
+            tokio::spawn(gateway::ws::serve(state.clone(), listener, acceptor, access));
+            Ok(Some(addr))
+        }
+        _ => bail!("Every listener needs exactly one of quic, tls, unix or websocket."),
+    }
+}
+
+/// Binds a QUIC endpoint on `listen` and returns the address it listens on.
+pub async fn bind(options: &Opt, state: &State, listen: &SocketAddr) -> Result<(SocketAddr, quinn::Incoming)> {
+    let mut transport_config = state.config.transport.build()?;
+    if state.config.gateway.http3 {
+        // HTTP/3 control and QPACK streams.
+        transport_config.stream_window_uni(3);
+    }
+    let mut server_config = quinn::ServerConfig::default();
+    server_config.transport = Arc::new(transport_config);
+    let mut server_config = quinn::ServerConfigBuilder::new(server_config);
+    let mut protocols = capabilities::alpn_protocols();
+    if state.config.gateway.http3 {
+        protocols.push(gateway::h3::ALPN_H3);
+    }
+    server_config.protocols(&protocols);
+
+    if options.keylog {
+        server_config.enable_keylog();
+    }
+
+    if options.stateless_retry {
+        server_config.use_stateless_retry(true);
+    }
+   
+    let (key, cert_chain) = security::init_security(&options.key, &options.cert).await?;
+    server_config.certificate(cert_chain, key)?;
+
+
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.listen(server_config.build());
+
+    let (endpoint, incoming) = endpoint.bind(listen)?;
+    let addr = endpoint.local_addr()?;
+    info!("Listening on {}.", addr);
+
+    Ok((addr, incoming))
+}
+
+pub async fn serve(state: Arc<State>, mut incoming: quinn::Incoming, access: Access) -> Result<()> {
+    while let Some(conn) = incoming.next().await {
+        info!("Connection incoming.");
+        tokio::spawn(
+            handle_connection(state.clone(), conn, access.clone()).unwrap_or_else(move |e| {
+                error!("Connection failed: {reason}", reason = e.to_string())
+            }),
+        );
+    }
+    
+    Ok(())
+}
+
+async fn handle_connection(state: Arc<State>, conn: quinn::Connecting, access: Access) -> Result<()> {
+    let conn = conn.await?;
+    if conn.connection.authentication_data().protocol.as_deref() == Some(gateway::h3::ALPN_H3) {
+        let span = info_span!("http3", remote = %conn.connection.remote_address());
+        return gateway::h3::serve(state, conn, access).instrument(span).await;
+    }
+
+    let quinn::NewConnection {
+        connection,
+        mut bi_streams,
+        mut uni_streams,
+        mut datagrams,
+        ..
+    } = conn;
+
+    // The broker protocol has no unidirectional streams; the window for
+    // them exists only for HTTP/3, so anything a broker client sends on one
+    // is refused instead of sitting in the receive buffer.
+    tokio::spawn(async move {
+        while let Some(Ok(mut stream)) = uni_streams.next().await {
+            let _ = stream.stop(0u32.into());
+        }
+    });
+
+    let span = info_span!(
+        "connection",
+        remote = %connection.remote_address(),
+        protocol = %connection
+            .authentication_data()
+            .protocol
+            .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned())
+    );
+
+    let format = capabilities::format_of(connection.authentication_data().protocol.as_deref());
+    let identity = if state.keys.enabled() {
+        let stream = match bi_streams.next().await {
+            Some(Ok(stream)) => stream,
+            _ => return Ok(()),
+        };
+        match authenticate(&state, format, stream).instrument(span.clone()).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                connection.close(1u32.into(), b"authentication failed");
+                return Err(e);
+            }
+        }
+    } else {
+        auth::Identity::Address(connection.remote_address().ip())
+    };
+
+    let session = Arc::new(Session {
+        identity,
+        flows: Some(Flows::new(connection.clone())),
+        format,
+        access,
+    });
+
+    {
+        let session = session.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(datagram)) = datagrams.next().await {
+                if let Some(flows) = &session.flows {
+                    flows.dispatch(datagram);
+                }
+            }
+        });
+    }
+
+    async {
+        info!("Established");
+
+        while let Some(stream) = bi_streams.next().await {
+            let stream = match stream {
+                Err(quinn::ConnectionError::ApplicationClosed {..}) => {
+                    info!("Connection closed.");
+                    return Ok(());
+                },
+                Err(e) => {
+                    return Err(e);
+                },
+                Ok(s) => s,
+            };
+
+            tokio::spawn(
+                handle_request(state.clone(), session.clone(), stream)
+                    .unwrap_or_else(move |e| error!("Failed: {reason}.", reason = e.to_string()))
+                    .instrument(info_span!("Request")),
+            );
+        }
+
+        Ok(())
+    }
+    .instrument(span)
+    .await?;
+
+    Ok(())
+}
+
+/// Serves the streams a client multiplexes over `io`, until it closes.
+///
+/// With `authenticate` set the first stream must authenticate the client,
+/// as on QUIC connections.
+async fn serve_mux<S>(state: Arc<State>, mut session: Session, io: S, authenticate: bool)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut streams = mux::accept(io);
+
+    if authenticate {
+        let (mut send, mut recv) = match streams.recv().await {
+            Some(stream) => stream,
+            None => return,
+        };
+        let result = request::authenticate(&state, session.format, &mut recv, &mut send).await;
+        let _ = send.shutdown().await;
+        match result {
+            Ok(identity) => session.identity = identity,
+            Err(e) => {
+                error!("Authentication failed: {reason}.", reason = e.to_string());
+                return;
+            }
+        }
+    }
+
+    info!(client = %session.identity, "Established");
+    let session = Arc::new(session);
+
+    while let Some((mut send, mut recv)) = streams.recv().await {
+        let state = state.clone();
+        let session = session.clone();
+
+        tokio::spawn(
+            async move {
+                request::handle_request(&state, &session, &mut recv, &mut send).await?;
+
+                send.shutdown()
+                    .await
+                    .map_err(|e| anyhow!("Failed to shutdown stream: {}", e))?;
+                info!("Complete.");
+                Ok(())
+            }
+            .unwrap_or_else(move |e: anyhow::Error| error!("Failed: {reason}.", reason = e.to_string()))
+            .instrument(info_span!("Request")),
+        );
+    }
+
+    info!("Connection closed.");
+}
+
+/// Authenticates the client on the first stream of its connection.
+async fn authenticate(state: &State, format: Format, (mut send, mut recv): (quinn::SendStream, quinn::RecvStream)) -> Result<auth::Identity> {
+    let result = request::authenticate(state, format, &mut recv, &mut send).await;
+    // Waits for the client to receive the answer before the connection may close.
+    let _ = send.finish().await;
+
+    match &result {
+        Ok(identity) => info!(client = %identity, "Authenticated."),
+        Err(e) => error!("Authentication failed: {reason}.", reason = e.to_string()),
+    }
+    result
+}
+
+async fn handle_request(state: Arc<State>, session: Arc<Session>, (mut send, mut recv): (quinn::SendStream, quinn::RecvStream)) -> Result<()> {
+    request::handle_request(&state, &session, &mut recv, &mut send).await?;
+
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("Failed to shutdown stream: {}", e))?;
+    info!("Complete.");
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! TLS over TCP listener for networks that drop UDP. Clients multiplex
+//! their streams with `mux` and get the same framing as over QUIC.
+
+use std::{net::SocketAddr, sync::Arc};
+
+extern crate anyhow;
+use anyhow::Result;
+use futures::{StreamExt, TryFutureExt};
+use rustls::Session as _;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, info_span};
+use tracing_futures::Instrument as _;
+
+use super::Opt;
+use crate::access::Access;
+use crate::auth;
+use crate::capabilities;
+use crate::security;
+use crate::session::Session;
+use crate::state::State;
+
+/// Binds a TLS listener on `listen`.
+pub async fn bind(options: &Opt, listen: &SocketAddr) -> Result<(SocketAddr, TcpListener, TlsAcceptor)> {
+    let config = security::tls_config(&options.key, &options.cert, &capabilities::alpn_protocols()).await?;
+    let listener = TcpListener::bind(listen).await?;
+    let addr = listener.local_addr()?;
+    info!("Listening on {} (TLS).", addr);
+
+    Ok((addr, listener, TlsAcceptor::from(Arc::new(config))))
+}
+
+pub async fn serve(state: Arc<State>, mut listener: TcpListener, acceptor: TlsAcceptor, access: Access) -> Result<()> {
+    let mut incoming = listener.incoming();
+    while let Some(tcp) = incoming.next().await {
+        let tcp = match tcp {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                error!("Accept failed: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(
+            handle_connection(state.clone(), tcp, acceptor.clone(), access.clone()).unwrap_or_else(move |e| {
+                error!("Connection failed: {reason}", reason = e.to_string())
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(state: Arc<State>, tcp: TcpStream, acceptor: TlsAcceptor, access: Access) -> Result<()> {
+    let remote = tcp.peer_addr()?;
+    tcp.set_nodelay(true)?;
+    let tls = acceptor.accept(tcp).await?;
+
+    let format = capabilities::format_of(tls.get_ref().1.get_alpn_protocol());
+    let session = Session {
+        identity: auth::Identity::Address(remote.ip()),
+        flows: None,
+        format,
+        access,
+    };
+
+    let span = info_span!("connection", remote = %remote, transport = "tls");
+    let authenticate = state.keys.enabled();
+    super::serve_mux(state, session, tls, authenticate).instrument(span).await;
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Unix socket listener for on-host tools. Callers are authorized by their
+//! peer credentials instead of TLS, and multiplex their streams with `mux`.
+
+use std::{
+    fs::Permissions,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+};
+
+extern crate anyhow;
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{StreamExt, TryFutureExt};
+use tokio::fs;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument as _;
+
+use crate::access::Access;
+use crate::auth::Identity;
+use crate::codec::Format;
+use crate::config::LocalConfig;
+use crate::session::Session;
+use crate::state::State;
+
+/// A bound socket and the user it belongs to.
+pub struct Listener {
+    listener: UnixListener,
+    owner: u32,
+}
+
+/// Binds a Unix socket on `path`, replacing a stale socket but nothing
+/// else. The socket is created in a private directory next to `path` and
+/// only moved into place once it has its final mode, so it is never
+/// reachable with looser permissions.
+pub async fn bind(state: &State, path: &Path) -> Result<Listener> {
+    match fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).await.context("Failed to remove stale socket.")?
+        }
+        Ok(_) => bail!("{} exists and is not a socket.", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("Failed to inspect socket path."),
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path.", path.display()))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .await
+        .context("Failed to create socket directory.")?;
+
+    let bound = place(state, &private.join(name), path).await;
+    let _ = fs::remove_dir(&private).await;
+    let listener = bound?;
+
+    let owner = fs::metadata(path).await?.uid();
+    info!("Listening on {}.", path.display());
+
+    Ok(Listener { listener, owner })
+}
+
+/// Binds on `tmp`, sets the configured mode and renames the socket to `path`.
+async fn place(state: &State, tmp: &Path, path: &Path) -> Result<UnixListener> {
+    let listener = UnixListener::bind(tmp).context("Failed to bind Unix socket.")?;
+    let placed = async {
+        fs::set_permissions(tmp, Permissions::from_mode(state.config.local.mode))
+            .await
+            .context("Failed to set socket permissions.")?;
+        fs::rename(tmp, path).await.context("Failed to move socket into place.")
+    }
+    .await;
+    if placed.is_err() {
+        let _ = fs::remove_file(tmp).await;
+    }
+    placed.map(|()| listener)
+}
+
+pub async fn serve(state: Arc<State>, listener: Listener, access: Access) -> Result<()> {
+    let Listener { mut listener, owner } = listener;
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Accept failed: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(
+            handle_connection(state.clone(), owner, stream, access.clone()).unwrap_or_else(move |e| {
+                error!("Connection failed: {reason}", reason = e.to_string())
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(state: Arc<State>, owner: u32, stream: UnixStream, access: Access) -> Result<()> {
+    let cred = stream.peer_cred()?;
+    let identity = Identity::Local {
+        uid: cred.uid,
+        gid: cred.gid,
+    };
+
+    if !authorized(&state.config.local, owner, cred.uid, cred.gid) {
+        warn!(client = %identity, "Local client not allowed.");
+        return Ok(());
+    }
+
+    let span = info_span!("connection", remote = %identity, transport = "unix");
+    let session = Session {
+        identity,
+        flows: None,
+        format: Format::default(),
+        access,
+    };
+    // Peer credentials already identify the caller.
+    super::serve_mux(state, session, stream, false).instrument(span).await;
+
+    Ok(())
+}
+
+/// Whether a peer with `uid` and `gid` may connect to a socket of the user
+/// `owner`: the owner always may, anyone else only when listed in `config`.
+pub fn authorized(config: &LocalConfig, owner: u32, uid: u32, gid: u32) -> bool {
+    uid == owner || config.uids.contains(&uid) || config.gids.contains(&gid)
+}
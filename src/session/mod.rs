@@ -0,0 +1,16 @@
+use crate::access::Access;
+use crate::auth::Identity;
+use crate::codec::Format;
+use crate::tunnel::datagram::Flows;
+
+/// Context shared by every request on one client connection.
+pub struct Session {
+    pub identity: Identity,
+    /// UDP flows carried in QUIC datagrams, absent if the transport has none.
+    pub flows: Option<Flows>,
+    /// Frame encoding the connection was negotiated for. Requests may still
+    /// switch a msgpack connection to JSON.
+    pub format: Format,
+    /// Commands the listener the client connected to serves.
+    pub access: Access,
+}
@@ -0,0 +1,73 @@
+extern crate anyhow;
+use anyhow::{anyhow, Result};
+
+use bollard::Docker;
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::auth::KeyStore;
+use crate::config::Config;
+use crate::limits::RateLimiter;
+use crate::metrics::Metrics;
+use crate::policy::HostLimits;
+use crate::registry::Registries;
+use crate::tenant::Tenants;
+
+/// Everything a request handler needs that outlives a single connection.
+pub struct State {
+    pub config: Config,
+    pub registries: Registries,
+    pub limiter: RateLimiter,
+    /// Caps Docker operations running at once.
+    pub operations: Semaphore,
+    /// Caps long-lived streams, apart from `operations`.
+    pub streams: Semaphore,
+    pub metrics: Metrics,
+    pub keys: KeyStore,
+    pub tenants: Tenants,
+    /// Held from the quota and overcommit checks of a create or update
+    /// until Docker carried it out, so concurrent requests cannot each pass
+    /// checks they would fail together.
+    pub admitting: Mutex<()>,
+    /// Limits of the host's containers for the overcommit check.
+    pub host_limits: HostLimits,
+}
+
+impl State {
+    pub async fn new(config: Config) -> Result<State> {
+        let registries = Registries::load(&config.registry).await?;
+        let keys = KeyStore::load(&config.auth).await?;
+        let tenants = Tenants::new(&config.tenants, &config.operators, keys.enabled())?;
+
+        Ok(State {
+            limiter: RateLimiter::new(&config.limits),
+            operations: Semaphore::new(config.limits.max_concurrent_operations),
+            streams: Semaphore::new(config.limits.max_concurrent_streams),
+            metrics: Metrics::default(),
+            config,
+            registries,
+            keys,
+            tenants,
+            admitting: Mutex::new(()),
+            host_limits: HostLimits::default(),
+        })
+    }
+
+    /// Client for the configured Docker daemon.
+    pub fn docker(&self) -> Result<Docker> {
+        let docker = match &self.config.docker.socket {
+            Some(socket) => {
+                let socket = socket
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Docker socket path is not UTF-8."))?;
+                Docker::connect_with_unix(
+                    socket,
+                    self.config.docker.timeout.unwrap_or(120),
+                    bollard::API_DEFAULT_VERSION,
+                )?
+            }
+            None => Docker::connect_with_local_defaults()?,
+        };
+
+        Ok(docker)
+    }
+}
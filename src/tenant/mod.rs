@@ -0,0 +1,241 @@
+//! Tenant namespaces for teams sharing a host.
+//!
+//! Containers belong to the tenant whose client created them, recorded in
+//! the `broker.tenant` label. Clients of a tenant only list, inspect and act
+//! on their own containers, those of other tenants look like they do not
+//! exist. Images and the daemon are shared by the whole host, so image
+//! builds, pulls, pushes and system information are for operators only.
+//! Operators see everything, any other client is refused once tenants are
+//! configured.
+
+use std::collections::{HashMap, HashSet};
+
+extern crate anyhow;
+use anyhow::{bail, Result};
+use bollard::container::{APIContainers, Config, InspectContainerOptions, ListContainersOptions};
+use bollard::Docker;
+use broker_proto::CommandType;
+
+use crate::auth::Identity;
+use crate::config::{OperatorsConfig, TenantConfig};
+use crate::error::{Error, ErrorCode};
+use crate::policy;
+
+/// Label holding the name of the tenant a container belongs to.
+pub const LABEL: &str = "broker.tenant";
+
+pub struct Tenants {
+    tenants: Vec<TenantConfig>,
+    operators: OperatorsConfig,
+}
+
+impl Tenants {
+    /// Tenants need clients that are told apart by their credentials, so
+    /// `authenticated` must be set when any are configured.
+    pub fn new(config: &[TenantConfig], operators: &OperatorsConfig, authenticated: bool) -> Result<Tenants> {
+        if !config.is_empty() && !authenticated {
+            bail!("Tenants need authentication, configure auth.keys_file or auth.token_key_file.");
+        }
+
+        let mut names = HashSet::new();
+        for tenant in config {
+            if tenant.name.is_empty() || tenant.name.contains('=') {
+                bail!("Tenant names must be non-empty and may not contain '='.");
+            }
+            if !names.insert(&tenant.name) {
+                bail!("Tenant {} is configured twice.", tenant.name);
+            }
+        }
+
+        Ok(Tenants {
+            tenants: config.to_vec(),
+            operators: operators.clone(),
+        })
+    }
+
+    /// Tenant `identity` belongs to, `None` for operators and when no
+    /// tenants are configured.
+    ///
+    /// Fails with `PermissionDenied` for clients that are neither in a
+    /// tenant nor operators.
+    pub fn of(&self, identity: &Identity) -> Result<Option<&TenantConfig>> {
+        let operators = &self.operators;
+        if self.tenants.is_empty() || member(identity, &operators.keys, &operators.tokens, &operators.uids) {
+            return Ok(None);
+        }
+
+        match self
+            .tenants
+            .iter()
+            .find(|tenant| member(identity, &tenant.keys, &tenant.tokens, &tenant.uids))
+        {
+            Some(tenant) => Ok(Some(tenant)),
+            None => bail!(denied(format!("Client {} is in no tenant.", identity))),
+        }
+    }
+
+    /// Stamps `config` with the tenant label and checks it against the
+    /// tenant's quotas.
+    ///
+    /// Limits count for every container of the tenant, running or not, so
+    /// starting a stopped one never goes over. Callers hold
+    /// `State::admitting` until the container is created.
+    pub async fn admit(&self, docker: &Docker, tenant: &TenantConfig, config: &mut Config<String>) -> Result<()> {
+        config
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .insert(LABEL.to_owned(), tenant.name.clone());
+
+        let containers = containers(docker, tenant).await?;
+
+        if let Some(max) = tenant.max_containers {
+            if containers.len() >= max {
+                bail!(denied(format!(
+                    "Tenant {} is at its quota of {} containers.",
+                    tenant.name, max
+                )));
+            }
+        }
+
+        let (memory, cpus) = policy::limits(config.host_config.as_ref());
+        check_resources(docker, tenant, &containers, None, Some(memory), Some(cpus)).await
+    }
+
+    /// Checks the limits an update of container `id` sets, as returned by
+    /// `policy::update_limits`, against the tenant's quotas. Callers hold
+    /// `State::admitting` until the container is updated.
+    pub async fn admit_update(
+        &self,
+        docker: &Docker,
+        tenant: &TenantConfig,
+        id: &str,
+        memory: Option<u64>,
+        cpus: Option<f64>,
+    ) -> Result<()> {
+        let containers = containers(docker, tenant).await?;
+        check_resources(docker, tenant, &containers, Some(id), memory, cpus).await
+    }
+}
+
+/// Whether `identity` is one of the listed keys, tokens or uids.
+fn member(identity: &Identity, keys: &[String], tokens: &[String], uids: &[u32]) -> bool {
+    match identity {
+        Identity::Key(name) => keys.contains(name),
+        Identity::Token(name) => tokens.contains(name),
+        Identity::Local { uid, .. } => uids.contains(uid),
+        Identity::Address(_) => false,
+    }
+}
+
+/// Every container of `tenant`, running or not.
+async fn containers(docker: &Docker, tenant: &TenantConfig) -> Result<Vec<APIContainers>> {
+    let mut options = ListContainersOptions::<String> {
+        all: true,
+        ..Default::default()
+    };
+    scope(tenant, &mut options.filters);
+
+    Ok(docker.list_containers(Some(options)).await?)
+}
+
+/// Checks that the tenant's `containers`, with the one being created or
+/// container `except` given a `memory` and `cpus` limit, stay within its
+/// quotas. Limits that are `None` stay as they are and are not checked.
+async fn check_resources(
+    docker: &Docker,
+    tenant: &TenantConfig,
+    containers: &[APIContainers],
+    except: Option<&str>,
+    memory: Option<u64>,
+    cpus: Option<f64>,
+) -> Result<()> {
+    let memory = memory.filter(|_| tenant.max_memory.is_some());
+    let cpus = cpus.filter(|_| tenant.max_cpus.is_some());
+    if memory.is_none() && cpus.is_none() {
+        return Ok(());
+    }
+
+    if memory == Some(0) {
+        bail!(denied(format!("Containers of tenant {} need a memory limit.", tenant.name)));
+    }
+    if cpus == Some(0.0) {
+        bail!(denied(format!("Containers of tenant {} need a CPU limit.", tenant.name)));
+    }
+
+    let (mut used_memory, mut used_cpus) = (0, 0.0);
+    for container in containers.iter().filter(|c| Some(c.id.as_str()) != except) {
+        let container = docker
+            .inspect_container(&container.id, None::<InspectContainerOptions>)
+            .await?;
+        let (memory, cpus) = policy::limits(Some(&container.host_config));
+        used_memory += memory;
+        used_cpus += cpus;
+    }
+
+    if let (Some(memory), Some(max)) = (memory, tenant.max_memory) {
+        if used_memory + memory > max {
+            bail!(denied(format!(
+                "Tenant {} has {} of its {} bytes of memory left.",
+                tenant.name,
+                max.saturating_sub(used_memory),
+                max
+            )));
+        }
+    }
+    if let (Some(cpus), Some(max)) = (cpus, tenant.max_cpus) {
+        if used_cpus + cpus > max {
+            bail!(denied(format!(
+                "Tenant {} has {} of its {} CPUs left.",
+                tenant.name,
+                (max - used_cpus).max(0.0),
+                max
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Narrows Docker `filters` to the containers of `tenant`.
+pub fn scope(tenant: &TenantConfig, filters: &mut HashMap<String, Vec<String>>) {
+    filters
+        .entry("label".to_owned())
+        .or_insert_with(Vec::new)
+        .push(format!("{}={}", LABEL, tenant.name));
+}
+
+/// Whether `cmd` acts on the whole host rather than on containers, and so
+/// is refused to clients of a tenant.
+pub fn host_wide(cmd: &CommandType) -> bool {
+    matches!(cmd, CommandType::Build | CommandType::Pull | CommandType::Push | CommandType::System)
+}
+
+/// Fails with `PermissionDenied` when `cmd` is not open to tenants.
+pub fn check_command(tenant: &TenantConfig, cmd: &CommandType) -> Result<()> {
+    if host_wide(cmd) {
+        bail!(Error::new(
+            ErrorCode::PermissionDenied,
+            format!("Tenant {} may not use {:?}, images and the daemon are shared by the host.", tenant.name, cmd),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fails with `NotFound` unless container `name` belongs to `tenant`.
+pub async fn check_owner(docker: &Docker, tenant: &TenantConfig, name: &str) -> Result<()> {
+    let container = docker
+        .inspect_container(name, None::<InspectContainerOptions>)
+        .await?;
+
+    let owner = container.config.labels.as_ref().and_then(|labels| labels.get(LABEL));
+    if owner != Some(&tenant.name) {
+        bail!(Error::new(ErrorCode::NotFound, format!("No such container: {}", name)));
+    }
+
+    Ok(())
+}
+
+fn denied(message: String) -> Error {
+    Error::new(ErrorCode::PermissionDenied, message)
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+extern crate anyhow;
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+/// Datagrams waiting for a flow's socket before new ones are dropped.
+const FLOW_QUEUE: usize = 256;
+
+/// UDP flows of one QUIC connection.
+///
+/// Each datagram starts with the big endian `u32` flow ID the broker handed
+/// out when the flow was opened, followed by the UDP payload.
+pub struct Flows {
+    connection: quinn::Connection,
+    next_id: AtomicU32,
+    flows: Mutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+}
+
+impl Flows {
+    pub fn new(connection: quinn::Connection) -> Flows {
+        Flows {
+            connection,
+            next_id: AtomicU32::new(0),
+            flows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Binds a socket connected to `addr` and returns the new flow ID.
+    pub async fn open(&self, addr: SocketAddr) -> Result<u32> {
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+
+        let socket = UdpSocket::bind(bind).await.context("Failed to bind UDP socket.")?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect UDP socket to {}.", addr))?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+        self.flows.lock().unwrap().insert(id, tx);
+
+        tokio::spawn(run_flow(id, self.connection.clone(), socket, rx));
+        info!("Opened UDP flow {} to {}.", id, addr);
+
+        Ok(id)
+    }
+
+    pub fn close(&self, id: u32) {
+        if self.flows.lock().unwrap().remove(&id).is_some() {
+            info!("Closed UDP flow {}.", id);
+        }
+    }
+
+    /// Hands a datagram received from the client to its flow.
+    ///
+    /// Datagrams for unknown flows or full queues are dropped, the same as
+    /// the network would.
+    pub fn dispatch(&self, mut datagram: Bytes) {
+        if datagram.len() < 4 {
+            debug!("Dropping datagram without flow ID.");
+            return;
+        }
+        let id = datagram.get_u32();
+
+        match self.flows.lock().unwrap().get_mut(&id) {
+            Some(tx) => {
+                if tx.try_send(datagram).is_err() {
+                    debug!("Dropping datagram for busy flow {}.", id);
+                }
+            }
+            None => debug!("Dropping datagram for unknown flow {}.", id),
+        }
+    }
+}
+
+async fn run_flow(id: u32, connection: quinn::Connection, socket: UdpSocket, mut rx: mpsc::Receiver<Bytes>) {
+    let (mut socket_recv, mut socket_send) = socket.split();
+
+    let upstream = async {
+        while let Some(datagram) = rx.recv().await {
+            if let Err(e) = socket_send.send(&datagram).await {
+                debug!("Flow {} send failed: {}", id, e);
+            }
+        }
+    };
+
+    let downstream = async {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match socket_recv.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("Flow {} receive failed: {}", id, e);
+                    continue;
+                }
+            };
+
+            let mut datagram = BytesMut::with_capacity(4 + n);
+            datagram.put_u32(id);
+            datagram.put_slice(&buf[..n]);
+
+            match connection.send_datagram(datagram.freeze()) {
+                Ok(()) => {}
+                Err(quinn::SendDatagramError::TooLarge) => {
+                    debug!("Flow {} reply of {} bytes too large for a datagram.", id, n);
+                }
+                Err(e) => {
+                    debug!("Flow {} stopped: {}", id, e);
+                    return;
+                }
+            }
+        }
+    };
+
+    futures::pin_mut!(upstream, downstream);
+    futures::future::select(upstream, downstream).await;
+}
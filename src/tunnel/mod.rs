@@ -0,0 +1,69 @@
+use std::net::{IpAddr, SocketAddr};
+
+extern crate anyhow;
+use anyhow::{anyhow, Context, Result};
+use bollard::Docker;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::info;
+
+use crate::error::{Error, ErrorCode};
+
+pub mod datagram;
+
+/// Finds the address `port` of container `name` is reachable at from the host.
+pub async fn resolve(docker: &Docker, name: &str, port: u16) -> Result<SocketAddr> {
+    let container = docker.inspect_container(name, None).await?;
+    let settings = &container.network_settings;
+
+    let ip = Some(settings.ip_address.as_str())
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| {
+            settings
+                .networks
+                .values()
+                .map(|n| n.ip_address.as_str())
+                .find(|ip| !ip.is_empty())
+        })
+        .ok_or_else(|| Error::new(ErrorCode::Conflict, format!("Container {} has no IP address.", name)))?;
+
+    let ip: IpAddr = ip
+        .parse()
+        .with_context(|| format!("Container {} has an invalid IP address.", name))?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+pub async fn connect(docker: &Docker, name: &str, port: u16) -> Result<TcpStream> {
+    let addr = resolve(docker, name, port).await?;
+
+    TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}:{}.", name, port))
+}
+
+/// Copies bytes between the client stream and `tcp` until both sides are done.
+///
+/// `rest` holds whatever the client sent after the tunnel header.
+pub async fn splice<R, W>(mut tcp: TcpStream, rest: Vec<u8>, recv: &mut R, send: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut tcp_recv, mut tcp_send) = tcp.split();
+
+    let upstream = async {
+        tcp_send.write_all(&rest).await?;
+        let n = io::copy(recv, &mut tcp_send).await?;
+        tcp_send.shutdown().await?;
+        Ok::<_, io::Error>(n + rest.len() as u64)
+    };
+    let downstream = io::copy(&mut tcp_recv, send);
+
+    let (up, down) = futures::try_join!(upstream, downstream)
+        .map_err(|e| anyhow!("Tunnel failed: {}", e))?;
+
+    info!("Tunnel closed, {} bytes up, {} bytes down.", up, down);
+
+    Ok(())
+}
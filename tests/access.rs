@@ -0,0 +1,73 @@
+//! Read-only mode and per-listener command allowlists.
+
+mod support;
+
+use broker_proto::{Body, CommandType};
+use quic_server::access::Access;
+use quic_server::error::ErrorCode;
+
+use support::{code, CONTAINER};
+
+#[tokio::test]
+async fn read_only() {
+    let h = support::start_with("", &["--read-only"]).await;
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    let err = h.client.stop(CONTAINER, None).await.unwrap_err();
+    assert_eq!(err.to_string(), "Stop is not allowed on this listener.");
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[tokio::test]
+async fn hello_lists_allowed_commands() {
+    let h = support::start_with("", &["--read-only"]).await;
+
+    match h.client.hello().await.unwrap() {
+        Body::Hello { commands, .. } => {
+            assert!(commands.contains(&CommandType::Hello));
+            assert!(commands.contains(&CommandType::Log));
+            assert!(!commands.contains(&CommandType::Create));
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn read_only_next_to_full_control() {
+    let h = support::start("[[listeners]]\nquic = \"127.0.0.1:0\"\nread_only = true\n").await;
+    let monitoring = h.connect(&h.listeners[0].unwrap()).await;
+
+    let err = monitoring.stop(CONTAINER, None).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+
+    h.client.stop(CONTAINER, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn command_allowlist() {
+    let h = support::start("[[listeners]]\nquic = \"127.0.0.1:0\"\ncommands = [\"List\", \"Restart\"]\n").await;
+    let client = h.connect(&h.listeners[0].unwrap()).await;
+
+    client.list().await.unwrap();
+    // Allowed, so it gets as far as checking its arguments.
+    let err = client.call(CommandType::Restart, None).await.unwrap_err();
+    assert_eq!(err.to_string(), "No parameter received.");
+
+    let err = client.stop(CONTAINER, None).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[test]
+fn unknown_command() {
+    let err = Access::new(false, Some(&["Lsit".to_owned()])).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command Lsit in listener commands.");
+
+    // A read-only allowlist keeps only its read-only commands.
+    let access = Access::new(true, Some(&["List".to_owned(), "Stop".to_owned()])).unwrap();
+    assert!(access.allows(&CommandType::List));
+    assert!(!access.allows(&CommandType::Stop));
+}
@@ -0,0 +1,186 @@
+//! Connections authenticating with API keys and signed tokens.
+
+mod support;
+
+use broker_proto::Body;
+use tempfile::TempDir;
+
+use quic_server::auth::KeyStore;
+use quic_server::config::AuthConfig;
+use quic_server::error::ErrorCode;
+
+use support::code;
+
+/// Hex SHA-256 of `secret`.
+const SECRET_SHA256: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+const TOKEN_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+struct Keys {
+    dir: TempDir,
+}
+
+impl Keys {
+    fn new(file: &str) -> Keys {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("keys.toml"), file).unwrap();
+        std::fs::write(dir.path().join("token.key"), TOKEN_KEY).unwrap();
+        Keys { dir }
+    }
+
+    fn config(&self, ttl: u64) -> String {
+        format!(
+            "[auth]\nkeys_file = {:?}\ntoken_key_file = {:?}\ntoken_ttl_secs = {}\n",
+            self.dir.path().join("keys.toml"),
+            self.dir.path().join("token.key"),
+            ttl
+        )
+    }
+
+    fn write(&self, file: &str) {
+        std::fs::write(self.dir.path().join("keys.toml"), file).unwrap();
+    }
+
+    async fn issue(&self, name: &str, ttl: u64) -> String {
+        let config = AuthConfig {
+            keys_file: None,
+            token_key_file: Some(self.dir.path().join("token.key")),
+            token_ttl_secs: ttl,
+        };
+        KeyStore::load(&config).await.unwrap().issue(name).unwrap()
+    }
+}
+
+fn key_file(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| format!("[[keys]]\nname = {:?}\nsha256 = {:?}\n", name, SECRET_SHA256))
+        .collect()
+}
+
+#[tokio::test]
+async fn api_key() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+
+    match h.client.authenticate("secret").await.unwrap() {
+        Body::Identity(identity) => assert_eq!(identity, "key ci"),
+        body => panic!("Unexpected body {:?}", body),
+    }
+    h.client.list().await.unwrap();
+}
+
+#[tokio::test]
+async fn required() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+
+    let err = h.client.list().await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[tokio::test]
+async fn unknown_key() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+
+    let err = h.client.authenticate("guess").await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[tokio::test]
+async fn only_on_first_stream() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+
+    h.client.authenticate("secret").await.unwrap();
+    let err = h.client.authenticate("secret").await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::InvalidArgument);
+}
+
+#[tokio::test]
+async fn key_revoked_without_restart() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+    h.client.authenticate("secret").await.unwrap();
+
+    keys.write("");
+    let client = h.reconnect().await;
+    let err = client.authenticate("secret").await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+
+    // Connections authenticated before keep their identity.
+    h.client.list().await.unwrap();
+}
+
+#[tokio::test]
+async fn key_file_replaced_or_removed() {
+    let keys = Keys::new(&key_file(&["ci"]));
+    let h = support::start(&keys.config(60)).await;
+
+    // Swapped in by rename with the same length, as config management does.
+    let replacement = keys.dir.path().join("keys.toml.new");
+    std::fs::write(&replacement, key_file(&["ci"]).replace(SECRET_SHA256, &"0".repeat(64))).unwrap();
+    std::fs::rename(&replacement, keys.dir.path().join("keys.toml")).unwrap();
+    let err = h.client.authenticate("secret").await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+
+    keys.write(&key_file(&["ci", "cd"]));
+    h.reconnect().await.authenticate("secret").await.unwrap();
+
+    std::fs::remove_file(keys.dir.path().join("keys.toml")).unwrap();
+    let err = h.reconnect().await.authenticate("secret").await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[tokio::test]
+async fn api_key_shaped_like_token() {
+    // SHA-256 of `deploy:1:ab`.
+    let keys = Keys::new(
+        "[[keys]]\nname = \"deploy\"\nsha256 = \"ac506a88280f7f2082b52940dc3dfec47d064de0c9018cc80320913bb2669633\"\n",
+    );
+    let h = support::start(&keys.config(60)).await;
+
+    match h.client.authenticate("deploy:1:ab").await.unwrap() {
+        Body::Identity(identity) => assert_eq!(identity, "key deploy"),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn signed_token() {
+    let keys = Keys::new("");
+    let h = support::start(&keys.config(60)).await;
+
+    let token = keys.issue("dashboard", 60).await;
+    match h.client.authenticate(&token).await.unwrap() {
+        Body::Identity(identity) => assert_eq!(identity, "key dashboard"),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    // A token whose signature does not match its name.
+    let forged = token.replacen("dashboard", "admin", 1);
+    let err = h.reconnect().await.authenticate(&forged).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+}
+
+#[tokio::test]
+async fn expired_token() {
+    let keys = Keys::new("");
+    let h = support::start(&keys.config(60)).await;
+
+    let token = keys.issue("dashboard", 0).await;
+    let err = h.client.authenticate(&token).await.unwrap_err();
+    assert!(err.to_string().contains("expired"), "{}", err);
+}
+
+#[tokio::test]
+async fn token_revoked_without_restart() {
+    let keys = Keys::new("");
+    let h = support::start(&keys.config(60)).await;
+    let token = keys.issue("dashboard", 60).await;
+
+    keys.write("revoked = [\"dashboard\"]\n");
+    let err = h.client.authenticate(&token).await.unwrap_err();
+    assert!(err.to_string().contains("revoked"), "{}", err);
+}
@@ -0,0 +1,89 @@
+//! Requests and responses round trip through both frame formats.
+
+mod support;
+
+use bollard::container::*;
+use broker_proto::{Arguments, Body, CommandType, Protocol};
+
+use quic_server::codec::Format;
+use quic_server::error::ErrorCode;
+use quic_server::request;
+
+fn requests() -> Vec<Protocol> {
+    vec![
+        Protocol::command(CommandType::List, None),
+        Protocol::command(
+            CommandType::Stop,
+            Some(Arguments::Stop {
+                name: "web".into(),
+                options: Some(StopContainerOptions { t: 10 }),
+                dry_run: false,
+            }),
+        ),
+        Protocol::command(
+            CommandType::Create,
+            Some(Arguments::Create {
+                config: Config {
+                    image: Some("nginx:1.17".into()),
+                    env: Some(vec!["A=ü".into()]),
+                    ..Default::default()
+                },
+                options: Some(CreateContainerOptions { name: "web".into() }),
+            }),
+        ),
+    ]
+}
+
+#[test]
+fn round_trip() {
+    for format in vec![Format::Msgpack, Format::Json] {
+        for req in requests() {
+            let encoded = format.encode(&req).unwrap();
+            assert_eq!(Format::of(&encoded), format);
+
+            let decoded = request::decode(&encoded, format).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", req));
+        }
+    }
+}
+
+#[test]
+fn json_frames_are_lines() {
+    let resp = Protocol::error(ErrorCode::NotFound, "No such container: db", Some(404));
+    let encoded = Format::Json.encode(&resp).unwrap();
+
+    assert_eq!(encoded.iter().filter(|b| **b == b'\n').count(), 1);
+    assert_eq!(encoded.last(), Some(&b'\n'));
+    assert!(serde_json::from_slice::<serde_json::Value>(&encoded).is_ok());
+}
+
+#[tokio::test]
+async fn json_client() {
+    let mut h = support::start("").await;
+    h.client.set_format(Format::Json);
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    let err = h.client.inspect("missing", None).await.unwrap_err();
+    assert!(err.to_string().contains("No such container: missing"), "{}", err);
+}
+
+#[tokio::test]
+async fn json_without_newline() {
+    let h = support::start("").await;
+
+    let mut header = Format::Json.encode(&Protocol::command(CommandType::List, None)).unwrap();
+    header.pop();
+
+    let (mut send, recv) = h.client.connection().unwrap().open_bi().await.unwrap();
+    send.write_all(&header).await.unwrap();
+    send.finish().await.unwrap();
+
+    let output = recv.read_to_end(1024 * 1024).await.unwrap();
+    let resp: Protocol = serde_json::from_slice(&output).unwrap();
+    assert!(resp.error.is_none(), "{:?}", resp.error);
+    assert!(matches!(resp.body, Body::ContainerList(_)));
+}
@@ -0,0 +1,517 @@
+//! Runs every command end to end through a broker server backed by a fake
+//! Docker daemon.
+
+mod support;
+
+use std::collections::HashMap;
+
+use bollard::container::*;
+use broker_proto::{Arguments, Body, CommandType, Protocol};
+
+use quic_server::capabilities::PROTOCOL_VERSION;
+use quic_server::codec::Format;
+use quic_server::error::{Error, ErrorCode};
+
+use support::{code, CONTAINER};
+
+#[tokio::test]
+async fn list() {
+    let h = support::start("").await;
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => {
+            assert_eq!(containers.len(), 1);
+            assert_eq!(containers[0].names, vec!["/web".to_owned()]);
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn inspect() {
+    let h = support::start("").await;
+
+    match h.client.inspect(CONTAINER, None).await.unwrap() {
+        Body::Container(container) => assert_eq!(container.name, "/web"),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn changes() {
+    let h = support::start("").await;
+
+    match h.client.changes(CONTAINER).await.unwrap() {
+        Body::Change(changes) => assert_eq!(changes.map_or(0, |c| c.len()), 3),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn stats() {
+    let h = support::start("").await;
+
+    let options = StatsOptions { stream: false };
+    match h.client.stats(CONTAINER, Some(options)).await.unwrap() {
+        Body::Stats(stats) => assert_eq!(stats.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn streamed_stats() {
+    let h = support::start("").await;
+
+    let mut stats = h.client.follow_stats(CONTAINER, StatsOptions { stream: true }).await.unwrap();
+    let mut samples = 0;
+    while let Some(body) = stats.next().await.unwrap() {
+        match body {
+            Body::Stats(stats) => samples += stats.len(),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(samples, 1);
+}
+
+#[tokio::test]
+async fn events() {
+    let h = support::start("").await;
+
+    let mut events = h.client.events(None).await.unwrap();
+    let mut actions = Vec::new();
+    while let Some(body) = events.next().await.unwrap() {
+        match body {
+            Body::Events(events) => actions.extend(events.into_iter().map(|e| e.action)),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(actions, vec!["start", "die"]);
+}
+
+#[tokio::test]
+async fn top() {
+    let h = support::start("").await;
+
+    match h.client.top(CONTAINER, None).await.unwrap() {
+        Body::TopResult(top) => assert_eq!(top.processes.len(), 2),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn logs() {
+    let h = support::start("").await;
+
+    let options = LogsOptions {
+        stdout: true,
+        ..Default::default()
+    };
+    let body = h.client.logs(CONTAINER, Some(options)).await.unwrap().last().await.unwrap();
+    match body {
+        Body::LogOutput(logs) => {
+            let text = logs.iter().map(|l| l.to_string()).collect::<String>();
+            assert_eq!(text, "listening on port 80\nready\n");
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn lifecycle() {
+    let h = support::start("").await;
+    let c = &h.client;
+
+    assert!(matches!(c.start(CONTAINER, None).await.unwrap(), Body::None));
+    assert!(matches!(c.stop(CONTAINER, None).await.unwrap(), Body::None));
+    assert!(matches!(c.restart(CONTAINER, None).await.unwrap(), Body::None));
+    let kill = KillContainerOptions { signal: "SIGTERM".to_owned() };
+    assert!(matches!(c.kill(CONTAINER, Some(kill)).await.unwrap(), Body::None));
+    assert!(matches!(c.remove(CONTAINER, None).await.unwrap(), Body::None));
+}
+
+#[tokio::test]
+async fn update() {
+    let h = support::start("").await;
+
+    let options = UpdateContainerOptions {
+        memory: Some(256 * 1024 * 1024),
+        ..Default::default()
+    };
+    assert!(matches!(h.client.update(CONTAINER, options).await.unwrap(), Body::None));
+}
+
+#[tokio::test]
+async fn prune() {
+    let h = support::start("").await;
+
+    let options = PruneContainersOptions::<String> { filters: HashMap::new() };
+    match h.client.prune(Some(options)).await.unwrap() {
+        Body::PrunedContainers(res) => assert_eq!(res.space_reclaimed, 1093),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn prune_dry_run() {
+    let h = support::start("").await;
+
+    match h.client.prune_dry_run(None).await.unwrap() {
+        Body::DryRun { containers, space_reclaimed } => {
+            assert_eq!(containers.len(), 1);
+            assert_eq!(containers[0].names, vec!["/migrate".to_owned()]);
+            // The same as the prune itself reports.
+            assert_eq!(space_reclaimed, 1093);
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn prune_dry_run_filters() {
+    let h = support::start("").await;
+
+    // The stopped container was created at 1589800000, 2020-05-18T11:06:40Z.
+    let filters = [
+        ("label!", "job=migrate"),
+        ("label!", "job"),
+        ("until", "1589700000"),
+        ("until", "2020-05-18T11:06:40Z"),
+        ("until", "2020-05-18T13:06:40+02:00"),
+    ];
+    for (key, value) in &filters {
+        let mut filters = HashMap::new();
+        filters.insert(key.to_string(), vec![value.to_string()]);
+        match h.client.prune_dry_run(Some(PruneContainersOptions { filters })).await.unwrap() {
+            Body::DryRun { containers, space_reclaimed } => {
+                assert!(containers.is_empty(), "{}={}", key, value);
+                assert_eq!(space_reclaimed, 0);
+            }
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+
+    let after = [
+        "24h",
+        "1h30m",
+        "1.5h",
+        "90m0.5s",
+        "1589800000.5",
+        "2020-05-18T11:06:41Z",
+        "2020-05-18t11:06:41z",
+        "2020-05-18 11:06:41Z",
+        "2020-05-18T09:06:40.5-02:00",
+    ];
+    for until in &after {
+        let mut filters = HashMap::new();
+        filters.insert("until".to_owned(), vec![until.to_string()]);
+        match h.client.prune_dry_run(Some(PruneContainersOptions { filters })).await.unwrap() {
+            Body::DryRun { containers, .. } => assert_eq!(containers.len(), 1, "until={}", until),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+
+    for until in &["yesterday", "1h30", "h", "2020-05-18", "2020-05-18T11:06:40", "2020-05-18_11:06:41Z", "2020-02-30T00:00:00Z"] {
+        let mut filters = HashMap::new();
+        filters.insert("until".to_owned(), vec![until.to_string()]);
+        let err = h.client.prune_dry_run(Some(PruneContainersOptions { filters })).await.unwrap_err();
+        assert_eq!(code(err), ErrorCode::InvalidArgument, "until={}", until);
+    }
+}
+
+#[tokio::test]
+async fn remove_dry_run() {
+    let h = support::start("").await;
+
+    let err = h.client.remove_dry_run(CONTAINER, None).await.unwrap_err();
+    assert_eq!(err.to_string(), "Container web is running, stop it first or force the removal.");
+    assert_eq!(code(err), ErrorCode::Conflict);
+
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    match h.client.remove_dry_run(CONTAINER, Some(options)).await.unwrap() {
+        Body::DryRun { containers, space_reclaimed } => {
+            assert_eq!(containers[0].names, vec!["/web".to_owned()]);
+            assert_eq!(space_reclaimed, 2048);
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn stop_dry_run() {
+    let h = support::start("").await;
+
+    match h.client.stop_dry_run(CONTAINER).await.unwrap() {
+        Body::DryRun { containers, space_reclaimed } => {
+            assert_eq!(containers[0].names, vec!["/web".to_owned()]);
+            assert_eq!(space_reclaimed, 0);
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn stop_matching() {
+    let h = support::start("").await;
+    let mut filters = HashMap::new();
+    filters.insert("label".to_owned(), vec!["app=web".to_owned()]);
+
+    match h.client.stop_matching_dry_run(filters.clone()).await.unwrap() {
+        Body::DryRun { containers, space_reclaimed } => {
+            assert_eq!(containers[0].names, vec!["/web".to_owned()]);
+            assert_eq!(space_reclaimed, 0);
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+    assert!(h.requests.lock().unwrap().iter().all(|r| !r.ends_with("/stop?")));
+
+    match h.client.stop_matching(filters, None).await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+    assert_eq!(h.requests.lock().unwrap().iter().filter(|r| r.starts_with("POST containers/")).count(), 1);
+
+    // No filters would mean every container on the host.
+    let err = h.client.stop_matching(HashMap::new(), None).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::InvalidArgument);
+}
+
+#[tokio::test]
+async fn create() {
+    let h = support::start("").await;
+
+    let config = Config {
+        image: Some("nginx".to_owned()),
+        ..Default::default()
+    };
+    let options = CreateContainerOptions { name: "web2".to_owned() };
+    match h.client.create(config, Some(options)).await.unwrap() {
+        Body::CreateContainerResults(res) => assert!(res.id.starts_with("0b4a5ce2")),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn system() {
+    let h = support::start("").await;
+
+    assert!(matches!(h.client.system().await.unwrap(), Body::System { .. }));
+}
+
+#[tokio::test]
+async fn hello() {
+    let h = support::start("").await;
+
+    match h.client.hello().await.unwrap() {
+        Body::Hello { protocol, runtimes, commands, .. } => {
+            assert_eq!(protocol, PROTOCOL_VERSION);
+            assert_eq!(runtimes, vec!["docker".to_owned()]);
+            assert!(commands.contains(&CommandType::Create));
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn hello_without_docker() {
+    let h = support::start("").await;
+    std::fs::remove_file(h.dir.path().join("docker.sock")).unwrap();
+
+    match h.client.hello().await.unwrap() {
+        Body::Hello { runtimes, commands, .. } => {
+            assert!(runtimes.is_empty());
+            assert!(commands.contains(&CommandType::Create));
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn incompatible_protocol() {
+    let h = support::start("").await;
+
+    let arg = Arguments::Hello { protocol: PROTOCOL_VERSION + 1 };
+    let err = h.client.call(CommandType::Hello, Some(arg)).await.unwrap_err();
+    assert!(err.to_string().starts_with("Unsupported protocol version"), "{}", err);
+}
+
+#[tokio::test]
+async fn incompatible_request_version() {
+    let h = support::start("").await;
+
+    for (version, expected) in vec![(Some(PROTOCOL_VERSION + 1), false), (None, true), (Some(PROTOCOL_VERSION), true)] {
+        let mut request = Protocol::command(CommandType::List, None);
+        request.version = version;
+
+        let (mut send, recv) = h.client.connection().unwrap().open_bi().await.unwrap();
+        send.write_all(&Format::Msgpack.encode(&request).unwrap()).await.unwrap();
+        send.finish().await.unwrap();
+
+        let output = recv.read_to_end(64 * 1024).await.unwrap();
+        let resp: Protocol = rmp_serde::from_slice(&output).unwrap();
+        match resp.error {
+            Some(message) => assert!(!expected && message.starts_with("Unsupported protocol version"), "{}", message),
+            None => assert!(expected, "{:?}", version),
+        }
+    }
+}
+
+#[tokio::test]
+async fn unknown_container() {
+    let h = support::start("").await;
+
+    let err = h.client.inspect("missing", None).await.unwrap_err();
+    assert!(err.to_string().contains("No such container: missing"), "{}", err);
+
+    let err = err.downcast::<Error>().unwrap();
+    assert_eq!(err.code, ErrorCode::NotFound);
+    assert_eq!(err.status, Some(404));
+}
+
+#[tokio::test]
+async fn invalid_argument() {
+    let h = support::start("").await;
+
+    let err = h
+        .client
+        .call(CommandType::Stop, Some(Arguments::Prune { options: None, dry_run: false }))
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Invalid argument.");
+    assert_eq!(code(err), ErrorCode::InvalidArgument);
+}
+
+#[tokio::test]
+async fn no_parameter() {
+    let h = support::start("").await;
+
+    for cmd in vec![
+        CommandType::Change,
+        CommandType::Container,
+        CommandType::Stats,
+        CommandType::Top,
+        CommandType::Log,
+        CommandType::Stop,
+        CommandType::Start,
+        CommandType::Kill,
+        CommandType::Restart,
+        CommandType::Prune,
+        CommandType::Remove,
+        CommandType::Update,
+        CommandType::Create,
+        CommandType::Hello,
+    ] {
+        let err = h.client.call(cmd, None).await.unwrap_err();
+        assert_eq!(err.to_string(), "No parameter received.");
+    }
+}
+
+#[tokio::test]
+async fn malformed_header() {
+    let h = support::start("").await;
+
+    let cases = vec![
+        // str32 claiming 4 GiB.
+        (&[0xdb, 0xff, 0xff, 0xff, 0xff, b'a'][..], "Request header too large."),
+        // array32 claiming 4 billion elements.
+        (&[0xdd, 0xff, 0xff, 0xff, 0xff, 0xc0][..], "Request header too large."),
+        (&[0x91; 1000][..], "Request nested too deeply."),
+        (&[0xc1][..], "Invalid request format."),
+        (&[0x92, 0xc0][..], "Invalid request format."),
+    ];
+
+    for (input, expected) in cases {
+        let (mut send, recv) = h.client.connection().unwrap().open_bi().await.unwrap();
+        send.write_all(input).await.unwrap();
+        send.finish().await.unwrap();
+
+        let output = recv.read_to_end(64 * 1024).await.unwrap();
+        let resp: Protocol = rmp_serde::from_slice(&output).unwrap();
+        assert_eq!(resp.error.as_deref(), Some(expected));
+    }
+}
+
+#[tokio::test]
+async fn rate_limited() {
+    let h = support::start("[limits.commands.List]\nrate = 0.001\nburst = 1.0\n").await;
+
+    h.client.list().await.unwrap();
+
+    let err = h.client.list().await.unwrap_err();
+    assert!(err.to_string().starts_with("Rate limited"), "{}", err);
+    let err = err.downcast::<Error>().unwrap();
+    assert_eq!(err.code, ErrorCode::RateLimited);
+    assert!(err.retry_after_ms.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn streams_have_own_slots() {
+    let h = support::start("[limits]\nmax_concurrent_operations = 1\nmax_concurrent_streams = 1\n").await;
+    let held = || LogsOptions {
+        follow: true,
+        stdout: true,
+        tail: "hold".into(),
+        ..Default::default()
+    };
+
+    let mut logs = h.client.logs(CONTAINER, Some(held())).await.unwrap();
+    assert!(logs.next().await.unwrap().is_some());
+
+    let err = h.client.logs(CONTAINER, Some(held())).await.unwrap().next().await.unwrap_err();
+    let err = err.downcast::<Error>().unwrap();
+    assert_eq!(err.code, ErrorCode::RateLimited);
+    assert_eq!(err.retry_after_ms, Some(100));
+
+    // One-off calls draw on the other slots.
+    h.client.list().await.unwrap();
+}
+
+#[tokio::test]
+async fn no_operations_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "[limits]\nmax_concurrent_operations = 0\n").unwrap();
+
+    let err = quic_server::config::Config::load(&Some(path)).await.unwrap_err();
+    assert_eq!(err.to_string(), "limits.max_concurrent_operations must be at least 1.");
+}
+
+#[tokio::test]
+async fn pull_keeps_reference_tags() {
+    let h = support::start("").await;
+
+    h.client.pull("nginx:1.19", None, None).await.unwrap();
+    h.client.pull("nginx@sha256:30dfa439718a17baafefadf16c5e7c9d0a1cde97b4fd84f63b69e13513be7097", None, None).await.unwrap();
+    h.client.pull("localhost:5000/nginx", None, None).await.unwrap_err();
+    h.client.pull("nginx", None, None).await.unwrap();
+    h.client.pull("nginx", Some("1.17".into()), None).await.unwrap();
+
+    let pulls = h
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| r.starts_with("POST images/create?"))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(pulls.len(), 4, "{:?}", pulls);
+    assert!(pulls[0].contains("fromImage=nginx:1.19") && !pulls[0].contains("tag=latest"), "{}", pulls[0]);
+    assert!(pulls[1].contains("fromImage=nginx@sha256:") && !pulls[1].contains("tag=latest"), "{}", pulls[1]);
+    assert!(pulls[2].contains("tag=latest"), "{}", pulls[2]);
+    assert!(pulls[3].contains("tag=1.17"), "{}", pulls[3]);
+
+    let err = h.client.pull("nginx:1.19", Some("1.17".into()), None).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::InvalidArgument);
+}
+
+#[tokio::test]
+async fn no_streams_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "[limits]\nmax_concurrent_streams = 0\n").unwrap();
+
+    let err = quic_server::config::Config::load(&Some(path)).await.unwrap_err();
+    assert_eq!(err.to_string(), "limits.max_concurrent_streams must be at least 1.");
+}
@@ -0,0 +1,70 @@
+//! Responses compressed with each codec decode to the same bodies.
+
+mod support;
+
+use bollard::container::LogsOptions;
+use broker_proto::Body;
+use quic_server::codec::{Codec, Format};
+use quic_server::metrics::Metrics;
+
+async fn check(codecs: &[Codec], config: &str) {
+    check_in(Format::Msgpack, codecs, config).await;
+}
+
+async fn check_in(format: Format, codecs: &[Codec], config: &str) {
+    let mut h = support::start(config).await;
+    h.client.set_format(format);
+    h.client.set_compression(codecs);
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    // Small frames stay plain, so a followed log mixes both on one stream.
+    let options = LogsOptions {
+        follow: true,
+        stdout: true,
+        ..Default::default()
+    };
+    let mut logs = h.client.logs(support::CONTAINER, Some(options)).await.unwrap();
+    let mut text = String::new();
+    while let Some(body) = logs.next().await.unwrap() {
+        match body {
+            Body::LogOutput(lines) => text.extend(lines.iter().map(|l| l.to_string())),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(text, "listening on port 80\nready\n");
+}
+
+#[tokio::test]
+async fn zstd() {
+    check(&[Codec::Zstd], "[compression]\nthreshold = 0\n").await;
+}
+
+#[tokio::test]
+async fn lz4() {
+    check(&[Codec::Lz4], "[compression]\nthreshold = 0\n").await;
+}
+
+#[tokio::test]
+async fn zstd_json() {
+    check_in(Format::Json, &[Codec::Zstd], "[compression]\nthreshold = 0\n").await;
+}
+
+#[tokio::test]
+async fn not_offered_by_server() {
+    check(&[Codec::Zstd], "[compression]\ncodecs = []\n").await;
+}
+
+#[test]
+fn compression_ratio() {
+    let metrics = Metrics::default();
+    assert_eq!(metrics.compression_ratio(), None);
+
+    metrics.record_compression(1000, 250);
+    metrics.record_compression(3000, 750);
+    assert_eq!(metrics.compressed_frames(), 2);
+    assert_eq!(metrics.compression_ratio(), Some(4.0));
+}
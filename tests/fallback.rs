@@ -0,0 +1,83 @@
+//! Requests over the TLS listener behave as they do over QUIC.
+
+mod support;
+
+use std::time::Duration;
+
+use bollard::container::LogsOptions;
+use broker_proto::Body;
+use quic_server::client::{Client, Trust};
+use quic_server::error::{Error, ErrorCode};
+
+#[tokio::test]
+async fn list_over_tls() {
+    let h = support::start_tls("").await;
+    assert!(h.client.connection().is_none());
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn concurrent_streams() {
+    let h = support::start_tls("").await;
+
+    let options = LogsOptions {
+        follow: true,
+        stdout: true,
+        ..Default::default()
+    };
+    let mut logs = h.client.logs(support::CONTAINER, Some(options)).await.unwrap();
+
+    // A second stream interleaved with the open log stream.
+    match h.client.inspect(support::CONTAINER, None).await.unwrap() {
+        Body::Container(container) => assert_eq!(container.name, "/web"),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    let mut text = String::new();
+    while let Some(body) = logs.next().await.unwrap() {
+        match body {
+            Body::LogOutput(lines) => text.extend(lines.iter().map(|l| l.to_string())),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(text, "listening on port 80\nready\n");
+}
+
+#[tokio::test]
+async fn errors_over_tls() {
+    let h = support::start_tls("").await;
+
+    let err = h.client.inspect("missing", None).await.unwrap_err();
+    let err = err.downcast::<Error>().unwrap();
+    assert_eq!(err.code, ErrorCode::NotFound);
+    assert_eq!(err.status, Some(404));
+}
+
+#[tokio::test]
+async fn falls_back_when_quic_is_unreachable() {
+    let h = support::start_tls("").await;
+
+    // A UDP socket that never answers, as if the network dropped QUIC.
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let quic = silent.local_addr().unwrap();
+
+    let client = Client::connect_with_fallback(
+        &quic,
+        &h.tls.unwrap(),
+        "localhost",
+        Trust::Authority(h.cert()),
+        Duration::from_millis(200),
+    )
+    .await
+    .unwrap();
+    assert!(client.connection().is_none());
+
+    match client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
@@ -0,0 +1,140 @@
+//! REST routes map onto the same commands the broker framing carries.
+
+mod support;
+
+use bollard::container::*;
+use broker_proto::{Arguments, CommandType};
+use bytes::Bytes;
+use hyper::{Method, Request, StatusCode};
+
+use quic_server::error::ErrorCode;
+use quic_server::gateway::{self, route};
+use quic_server::request::MAX_HEADER_SIZE;
+
+use support::CONTAINER;
+
+#[test]
+fn list() {
+    let (cmd, arg) = route(&Method::GET, "/containers", None, b"").unwrap();
+    assert!(matches!(cmd, CommandType::List));
+    assert!(arg.is_none());
+}
+
+#[test]
+fn stop_with_timeout() {
+    match route(&Method::POST, "/containers/web/stop", Some("t=5"), b"").unwrap() {
+        (CommandType::Stop, Some(Arguments::Stop { name, options, .. })) => {
+            assert_eq!(name, "web");
+            assert_eq!(options.unwrap().t, 5);
+        }
+        route => panic!("Unexpected route {:?}", route),
+    }
+}
+
+#[test]
+fn follow_logs() {
+    match route(&Method::GET, "/containers/web/logs", Some("follow&tail=10"), b"").unwrap() {
+        (CommandType::Log, Some(Arguments::Logs { name, options })) => {
+            let options = options.unwrap();
+            assert_eq!(name, "web");
+            assert!(options.follow);
+            assert_eq!(options.tail, "10");
+        }
+        route => panic!("Unexpected route {:?}", route),
+    }
+}
+
+#[test]
+fn create_from_body() {
+    let body = br#"{"Image": "nginx:1.17", "Env": ["A=1"]}"#;
+    match route(&Method::POST, "/containers", Some("name=web"), body).unwrap() {
+        (CommandType::Create, Some(Arguments::Create { config, options })) => {
+            assert_eq!(config.image.as_deref(), Some("nginx:1.17"));
+            assert_eq!(options.unwrap().name, "web");
+        }
+        route => panic!("Unexpected route {:?}", route),
+    }
+}
+
+#[test]
+fn rejected() {
+    let err = route(&Method::GET, "/images", None, b"").unwrap_err();
+    assert_eq!(err.code, ErrorCode::NotFound);
+
+    let err = route(&Method::PUT, "/containers/web", None, b"").unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+    let err = route(&Method::GET, "/containers/web/logs", Some("follow=maybe"), b"").unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidArgument);
+
+    let err = route(&Method::POST, "/containers", None, b"{").unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidArgument);
+}
+
+#[test]
+fn statuses() {
+    assert_eq!(gateway::status_of(ErrorCode::NotFound), StatusCode::NOT_FOUND);
+    assert_eq!(gateway::status_of(ErrorCode::RateLimited), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(gateway::status_of(ErrorCode::RuntimeUnavailable), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn prune_dry_run() {
+    match route(&Method::POST, "/containers/prune", Some("dry_run"), b"").unwrap() {
+        (CommandType::Prune, Some(Arguments::Prune { options, dry_run })) => {
+            assert!(options.is_none());
+            assert!(dry_run);
+        }
+        route => panic!("Unexpected route {:?}", route),
+    }
+}
+
+#[test]
+fn stop_matching() {
+    let query = r#"filters={"label":["app=web"]}&dry_run"#;
+    match route(&Method::POST, "/containers/stop", Some(query), b"").unwrap() {
+        (CommandType::Stop, Some(Arguments::StopMatching { filters, options, dry_run })) => {
+            assert_eq!(filters["label"], vec!["app=web".to_owned()]);
+            assert!(options.is_none());
+            assert!(dry_run);
+        }
+        route => panic!("Unexpected route {:?}", route),
+    }
+}
+
+/// Sends one HTTP/3 request to the harness' QUIC endpoint and returns the
+/// status and body of the response.
+async fn h3(h: &support::Harness, request: Request<quinn_h3::Body>) -> (StatusCode, String) {
+    let mut quic = quinn::ClientConfigBuilder::default();
+    quic.add_certificate_authority(quinn::Certificate::from_der(&h.cert()).unwrap()).unwrap();
+    let client = quinn_h3::client::Builder::with_quic_config(quic).build().unwrap();
+    let conn = client.connect(&h.addr, "localhost").unwrap().await.unwrap();
+
+    let (response, mut body) = conn.send_request(request).await.unwrap().await.unwrap();
+    let body = body.read_to_end().await.unwrap();
+    (response.status(), String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn http3_list() {
+    let h = support::start("[gateway]\nhttp3 = true\n").await;
+
+    let request = Request::get("https://localhost/containers").body(().into()).unwrap();
+    let (status, body) = h3(&h, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(CONTAINER));
+
+    // Broker clients keep working on the same endpoint.
+    h.client.list().await.unwrap();
+}
+
+#[tokio::test]
+async fn http3_body_limit() {
+    let h = support::start("[gateway]\nhttp3 = true\n").await;
+
+    let body = Bytes::from(vec![b' '; MAX_HEADER_SIZE + 1]);
+    let request = Request::post("https://localhost/containers").body(body.into()).unwrap();
+    let (status, body) = h3(&h, request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("exceeds"));
+}
@@ -0,0 +1,83 @@
+//! Requests over the Unix socket listener.
+
+mod support;
+
+use std::os::unix::fs::PermissionsExt;
+
+use broker_proto::Body;
+use quic_server::config::{Config, LocalConfig};
+use quic_server::error::ErrorCode;
+use quic_server::server::unix::{self, authorized};
+use quic_server::state::State;
+
+use support::code;
+
+#[tokio::test]
+async fn list_over_unix() {
+    let h = support::start_unix("").await;
+    assert!(h.client.connection().is_none());
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn socket_mode() {
+    let h = support::start_unix("[local]\nmode = 0o600\n").await;
+
+    let metadata = std::fs::metadata(h.dir.path().join("broker.sock")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+}
+
+#[tokio::test]
+async fn bind_keeps_other_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broker.sock");
+    std::fs::write(&path, "data").unwrap();
+    let state = State::new(Config::load(&None).await.unwrap()).await.unwrap();
+
+    assert!(unix::bind(&state, &path).await.is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+}
+
+#[tokio::test]
+async fn bind_replaces_stale_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broker.sock");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let state = State::new(Config::load(&None).await.unwrap()).await.unwrap();
+
+    unix::bind(&state, &path).await.unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+    // The private directory the socket was bound in is gone.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn rate_limited_per_local_user() {
+    let h = support::start_unix("[limits.commands.List]\nrate = 0.001\nburst = 1.0\n").await;
+
+    h.client.list().await.unwrap();
+
+    let err = h.client.list().await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::RateLimited);
+}
+
+#[test]
+fn peers_authorized() {
+    let config = LocalConfig {
+        uids: vec![1001],
+        gids: vec![2000],
+        ..Default::default()
+    };
+
+    // The owner, whatever its group.
+    assert!(authorized(&config, 1000, 1000, 1000));
+    assert!(authorized(&config, 1000, 1001, 1001));
+    assert!(authorized(&config, 1000, 1002, 2000));
+    assert!(!authorized(&config, 1000, 1002, 1002));
+    assert!(!authorized(&LocalConfig::default(), 1000, 0, 0));
+}
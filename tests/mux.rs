@@ -0,0 +1,57 @@
+//! The server side of a multiplexed connection, driven by raw frames.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use quic_server::mux::{self, FIN, MAX_STREAMS, RESET};
+
+async fn write_frame(io: &mut UnixStream, stream: u32, flags: u8, payload: &[u8]) {
+    let mut frame = stream.to_be_bytes().to_vec();
+    frame.push(flags);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    io.write_all(&frame).await.unwrap();
+}
+
+#[tokio::test]
+async fn late_frames_dropped() {
+    let (server, mut client) = UnixStream::pair().unwrap();
+    let mut streams = mux::accept(server);
+
+    write_frame(&mut client, 0, FIN, b"first").await;
+    let (_send, mut recv) = streams.recv().await.unwrap();
+    let mut content = Vec::new();
+    recv.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, b"first");
+
+    // The rest of stream 0 arrives after it closed, it must not open a new one.
+    write_frame(&mut client, 0, 0, b"late").await;
+    write_frame(&mut client, 1, FIN, b"second").await;
+    let (_send, mut recv) = streams.recv().await.unwrap();
+    let mut content = Vec::new();
+    recv.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, b"second");
+}
+
+#[tokio::test]
+async fn too_many_streams_reset() {
+    let (server, mut client) = UnixStream::pair().unwrap();
+    let mut streams = mux::accept(server);
+
+    let mut open = Vec::new();
+    for id in 0..MAX_STREAMS as u32 {
+        write_frame(&mut client, id, 0, b"x").await;
+        open.push(streams.recv().await.unwrap());
+    }
+
+    write_frame(&mut client, MAX_STREAMS as u32, 0, b"x").await;
+    let mut header = [0u8; 9];
+    client.read_exact(&mut header).await.unwrap();
+    assert_eq!(u32::from_be_bytes([header[0], header[1], header[2], header[3]]), MAX_STREAMS as u32);
+    assert_eq!(header[4], RESET);
+
+    // Closing a stream makes room for the next.
+    open.pop();
+    write_frame(&mut client, MAX_STREAMS as u32 + 1, 0, b"x").await;
+    streams.recv().await.unwrap();
+}
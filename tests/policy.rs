@@ -0,0 +1,361 @@
+//! Admission policies for container creation and updates.
+
+mod support;
+
+use bollard::container::*;
+use bollard::image::BuildImageOptions;
+use quic_server::policy;
+use serde_json::json;
+
+use support::{denied, uid, CONTAINER};
+
+fn config(image: &str, host_config: HostConfig<String>) -> Config<String> {
+    Config {
+        image: Some(image.to_owned()),
+        host_config: Some(host_config),
+        ..Default::default()
+    }
+}
+
+fn binds(binds: &[&str]) -> HostConfig<String> {
+    HostConfig {
+        binds: Some(binds.iter().map(|b| b.to_string()).collect()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn every_violation_reported() {
+    let h = support::start("").await;
+
+    let host_config = HostConfig {
+        privileged: Some(true),
+        network_mode: Some("host".to_owned()),
+        pid_mode: Some("host".to_owned()),
+        ..binds(&["/etc:/host/etc:ro"])
+    };
+    let err = h.client.create(config("nginx", host_config), None).await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Privileged mode is not allowed. Host networking is not allowed. \
+         Host PID namespace is not allowed. Bind mount of /etc is not allowed."
+    );
+    assert!(h.created.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn allowed_by_config() {
+    let h = support::start("[policy]\nallow_privileged = true\nallow_host_network = true\n").await;
+
+    let host_config = HostConfig {
+        privileged: Some(true),
+        network_mode: Some("host".to_owned()),
+        ..Default::default()
+    };
+    h.client.create(config("nginx", host_config), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn bind_paths() {
+    let h = support::start("[policy]\nbind_paths = [\"/srv/data\"]\n").await;
+
+    let host_config = binds(&["/srv/data/web:/usr/share/nginx/html:ro", "cache:/var/cache/nginx"]);
+    h.client.create(config("nginx", host_config), None).await.unwrap();
+
+    for bind in &["/srv/data/../../etc:/etc", "/srv/database:/data"] {
+        let err = h.client.create(config("nginx", binds(&[bind])), None).await.unwrap_err();
+        assert!(denied(err).starts_with("Rejected by policy: Bind mount of"), "{}", bind);
+    }
+}
+
+/// A container of `nginx` with `host_config`, given as Docker's JSON.
+fn from_json(host_config: serde_json::Value) -> Config<String> {
+    serde_json::from_value(json!({"Image": "nginx", "HostConfig": host_config})).unwrap()
+}
+
+#[tokio::test]
+async fn escapes_denied() {
+    let h = support::start("").await;
+
+    let cases = vec![
+        (json!({"CapAdd": ["ALL"]}), "Capability ALL is not allowed."),
+        (json!({"CapAdd": ["CAP_SYS_ADMIN"]}), "Capability CAP_SYS_ADMIN is not allowed."),
+        (
+            json!({"Devices": [{"PathOnHost": "/dev/sda", "PathInContainer": "/dev/sda", "CgroupPermissions": "rwm"}]}),
+            "Device mappings are not allowed.",
+        ),
+        (json!({"SecurityOpt": ["seccomp=unconfined"]}), "Security option seccomp=unconfined is not allowed."),
+        (json!({"SecurityOpt": ["apparmor:unconfined"]}), "Security option apparmor:unconfined is not allowed."),
+        (json!({"SecurityOpt": ["label=disable"]}), "Security option label=disable is not allowed."),
+        (json!({"IpcMode": "host"}), "Host IPC namespace is not allowed."),
+        (json!({"UTSMode": "host"}), "Host UTS namespace is not allowed."),
+        (json!({"UsernsMode": "host"}), "Host user namespace is not allowed."),
+        (
+            json!({"NetworkMode": "container:db"}),
+            "Joining the network namespace of another container is not allowed.",
+        ),
+        (json!({"PidMode": "container:db"}), "Joining the PID namespace of another container is not allowed."),
+        (json!({"IpcMode": "container:db"}), "Joining the IPC namespace of another container is not allowed."),
+        (json!({"VolumesFrom": ["db"]}), "Mounting the volumes of other containers is not allowed."),
+        (json!({"CgroupParent": "/"}), "Setting the parent cgroup is not allowed."),
+        (
+            json!({"Mounts": [{
+                "Type": "volume",
+                "Source": "root",
+                "Target": "/host",
+                "VolumeOptions": {"DriverConfig": {"Name": "local", "Options": {"type": "none", "o": "bind", "device": "/"}}}
+            }]}),
+            "Bind mount of / is not allowed.",
+        ),
+    ];
+
+    for (host_config, violation) in cases {
+        let err = h.client.create(from_json(host_config), None).await.unwrap_err();
+        assert_eq!(denied(err), format!("Rejected by policy: {}", violation));
+    }
+    assert!(h.created.lock().unwrap().is_empty());
+
+    // Confinement options that only tighten are fine.
+    let host_config = json!({"CapDrop": ["ALL"], "SecurityOpt": ["no-new-privileges", "seccomp=/etc/docker/strict.json"]});
+    h.client.create(from_json(host_config), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn escapes_allowed_by_config() {
+    let h = support::start(
+        "[policy]\nallow_capabilities = [\"net_admin\"]\nallow_devices = true\nallow_unconfined = true\n\
+         allow_host_ipc = true\nallow_host_uts = true\nallow_host_userns = true\n\
+         allow_container_namespaces = true\nallow_volumes_from = true\nallow_cgroup_parent = true\n",
+    )
+    .await;
+
+    let host_config = json!({
+        "CapAdd": ["CAP_NET_ADMIN"],
+        "Devices": [{"PathOnHost": "/dev/fuse", "PathInContainer": "/dev/fuse", "CgroupPermissions": "rwm"}],
+        "SecurityOpt": ["seccomp=unconfined"],
+        "IpcMode": "host",
+        "UTSMode": "host",
+        "UsernsMode": "host",
+        "NetworkMode": "container:db",
+        "PidMode": "container:db",
+        "VolumesFrom": ["db"],
+        "CgroupParent": "/batch"
+    });
+    h.client.create(from_json(host_config), None).await.unwrap();
+
+    // Listing one capability does not allow the rest.
+    let err = h.client.create(from_json(json!({"CapAdd": ["ALL"]})), None).await.unwrap_err();
+    assert_eq!(denied(err), "Rejected by policy: Capability ALL is not allowed.");
+}
+
+#[tokio::test]
+async fn bind_paths_resolve_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let allowed = dir.path().join("data");
+    std::fs::create_dir(&allowed).unwrap();
+    std::os::unix::fs::symlink("/", allowed.join("root")).unwrap();
+
+    let h = support::start(&format!("[policy]\nbind_paths = [{:?}]\n", allowed)).await;
+
+    let escape = allowed.join("root/etc");
+    let bind = format!("{}:/host", escape.display());
+    let err = h.client.create(config("nginx", binds(&[&bind])), None).await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        format!("Rejected by policy: Bind mount of {} is not allowed.", escape.display())
+    );
+
+    // Sources Docker has yet to create are fine below an allowed path.
+    let bind = format!("{}:/data", allowed.join("web/html").display());
+    h.client.create(config("nginx", binds(&[&bind])), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn images_and_registries() {
+    let h = support::start("[policy]\nimages = [\"nginx\", \"docker.io/team/*\", \"redis:6\"]\n").await;
+
+    let allowed = [
+        "nginx",
+        "nginx:1.19",
+        "docker.io/library/nginx@sha256:abc",
+        "index.docker.io/library/nginx",
+        "docker.io/team/api:2",
+        "team/api",
+        "team/tools/lint",
+        "redis:6",
+    ];
+    for image in &allowed {
+        h.client.create(config(image, Default::default()), None).await.unwrap();
+    }
+
+    for image in &["nginx-proxy", "team-evil/api", "library/team/api", "redis", "redis:7"] {
+        let err = h.client.create(config(image, Default::default()), None).await.unwrap_err();
+        assert_eq!(denied(err), format!("Rejected by policy: Image {} is not allowed.", image));
+    }
+
+    let err = h.client.create(config("quay.io/team/api", Default::default()), None).await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Registry quay.io is not allowed. Image quay.io/team/api is not allowed."
+    );
+}
+
+#[tokio::test]
+async fn builds() {
+    let h = support::start("").await;
+
+    let options = BuildImageOptions {
+        t: "quay.io/team/api".to_owned(),
+        remote: "https://example.com/context.tar".to_owned(),
+        networkmode: "host".to_owned(),
+        ..Default::default()
+    };
+    let err = h.client.build(options, b"context").await.unwrap().next().await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Registry quay.io is not allowed. \
+         Building from a remote context is not allowed. Host networking is not allowed."
+    );
+
+    // A built image could take any allowed name.
+    let h = support::start("[policy]\nimages = [\"nginx\"]\n").await;
+    let err = h.client.build(Default::default(), b"context").await.unwrap().next().await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Image builds are not allowed while images are restricted."
+    );
+    assert!(h.requests.lock().unwrap().iter().all(|r| !r.starts_with("POST build")));
+}
+
+#[tokio::test]
+async fn build_caps_and_defaults() {
+    let policy: quic_server::config::PolicyConfig = toml::from_str(&format!(
+        "[defaults]\nmemory = 268435456\ncpus = 0.5\n\n[[update.caps]]\nuids = [{}]\nmax_memory = 536870912\n",
+        uid()
+    ))
+    .unwrap();
+    let registries = quic_server::registry::Registries::load(&Default::default()).await.unwrap();
+    let local = quic_server::auth::Identity::Local { uid: uid(), gid: 0 };
+    let other = quic_server::auth::Identity::Local { uid: uid() + 1, gid: 0 };
+
+    let mut options = BuildImageOptions::<String>::default();
+    let err = policy::admit_build(&policy, &registries, &local, &mut options).unwrap_err();
+    assert_eq!(denied(err), "Rejected by policy: Build memory limit must be at most 536870912 bytes.");
+
+    // Clients without a cap get the defaults.
+    policy::admit_build(&policy, &registries, &other, &mut options).unwrap();
+    assert_eq!(options.memory, Some(268435456));
+    assert_eq!(options.cpuquota, Some(50_000));
+    assert_eq!(options.cpuperiod, Some(100_000));
+}
+
+#[tokio::test]
+async fn defaults() {
+    let h = support::start(
+        "[policy.defaults]\nmemory = 268435456\ncpus = 0.5\nlabels = { team = \"web\", env = \"dev\" }\n",
+    )
+    .await;
+
+    let mut config = config(
+        "nginx",
+        HostConfig {
+            memory: Some(1 << 30),
+            ..Default::default()
+        },
+    );
+    config.labels = Some(vec![("env".to_owned(), "prod".to_owned())].into_iter().collect());
+    h.client.create(config, None).await.unwrap();
+
+    let created = h.created.lock().unwrap();
+    // What the client set is kept, only the rest is filled in.
+    assert_eq!(created[0]["HostConfig"]["Memory"], 1 << 30);
+    assert_eq!(created[0]["HostConfig"]["NanoCpus"], 500_000_000);
+    assert_eq!(created[0]["Labels"]["env"], "prod");
+    assert_eq!(created[0]["Labels"]["team"], "web");
+}
+
+fn update(memory: Option<u64>, nano_cpus: Option<u64>) -> UpdateContainerOptions {
+    UpdateContainerOptions {
+        memory: memory.map(|m| m as _),
+        nano_cpus: nano_cpus.map(|n| n as _),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn update_caps_per_role() {
+    let uid = uid();
+    let h = support::start_unix(&format!(
+        "[[policy.update.caps]]\nuids = [{}]\nmax_memory = 1073741824\nmax_cpus = 2.0\n",
+        uid
+    ))
+    .await;
+
+    let err = h.client.update(CONTAINER, update(Some(2 << 30), Some(4_000_000_000))).await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Memory limit must be at most 1073741824 bytes. CPU limit must be at most 2 CPUs."
+    );
+
+    h.client.update(CONTAINER, update(Some(512 << 20), Some(1_500_000_000))).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_caps_per_tenant() {
+    let uid = uid();
+    let h = support::start_unix(&format!(
+        "[auth]\nkeys_file = \"/nonexistent/keys.toml\"\n\n[[tenants]]\nname = \"blue\"\nuids = [{}]\n\n\
+         [[policy.update.caps]]\ntenant = \"blue\"\nmax_memory = 1073741824\n",
+        uid
+    ))
+    .await;
+
+    let err = h.client.update(CONTAINER, update(Some(2 << 30), None)).await.unwrap_err();
+    assert_eq!(denied(err), "Rejected by policy: Memory limit must be at most 1073741824 bytes.");
+
+    // Clients outside the tenant are not capped.
+    let h = support::start("[[policy.update.caps]]\ntenant = \"blue\"\nmax_memory = 1073741824\n").await;
+    h.client.update(CONTAINER, update(Some(2 << 30), None)).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_overcommit() {
+    // The fake host has 16679079936 bytes of memory and 8 CPUs.
+    let h = support::start("[policy.update]\nmemory_overcommit = 1.0\ncpu_overcommit = 2.0\n").await;
+
+    let err = h.client.update(CONTAINER, update(Some(16679079937), Some(17_000_000_000))).await.unwrap_err();
+    assert_eq!(
+        denied(err),
+        "Rejected by policy: Memory limits would add up to 16679079937 bytes, over 1 times the host's \
+         16679079936 bytes. CPU limits would add up to 17 CPUs, over 2 times the host's 8 CPUs."
+    );
+
+    h.client.update(CONTAINER, update(Some(8 << 30), Some(16_000_000_000))).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_without_overcommit_skips_host_scan() {
+    let h = support::start("").await;
+
+    h.client.update(CONTAINER, update(Some(1 << 30), None)).await.unwrap();
+    assert!(h.requests.lock().unwrap().iter().all(|r| !r.starts_with("GET containers/json")));
+}
+
+#[test]
+fn period_rescales_quota() {
+    let current = HostConfig {
+        cpu_quota: Some(100_000),
+        cpu_period: Some(100_000),
+        ..Default::default()
+    };
+
+    // A shorter period alone turns the container's one CPU into 100.
+    let update = UpdateContainerOptions {
+        cpu_period: Some(1000),
+        ..Default::default()
+    };
+    assert_eq!(policy::update_limits(&current, &update), (None, Some(100.0)));
+
+    // Without a quota a period changes nothing.
+    assert_eq!(policy::update_limits(&HostConfig::default(), &update), (None, None));
+}
@@ -0,0 +1,26 @@
+//! Image references as Docker reads them.
+
+use quic_server::registry::{registry_of, Reference};
+
+#[test]
+fn references() {
+    let cases = [
+        ("nginx", "docker.io", "library/nginx", None, None),
+        ("nginx:1.19", "docker.io", "library/nginx", Some("1.19"), None),
+        ("docker.io/library/nginx", "docker.io", "library/nginx", None, None),
+        ("index.docker.io/team/app:v2", "docker.io", "team/app", Some("v2"), None),
+        ("localhost:5000/app", "localhost:5000", "app", None, None),
+        ("registry.example.com:443/team/app:1@sha256:ab", "registry.example.com:443", "team/app", Some("1"), Some("sha256:ab")),
+    ];
+
+    for (image, registry, repository, tag, digest) in cases.iter() {
+        let reference = Reference::parse(image);
+        assert_eq!(reference.registry, *registry, "{}", image);
+        assert_eq!(reference.repository, *repository, "{}", image);
+        assert_eq!(reference.tag.as_deref(), *tag, "{}", image);
+        assert_eq!(reference.digest.as_deref(), *digest, "{}", image);
+        assert_eq!(registry_of(image), *registry, "{}", image);
+    }
+
+    assert_eq!(Reference::parse("nginx").name(), Reference::parse("docker.io/library/nginx:1.19").name());
+}
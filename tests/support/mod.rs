@@ -0,0 +1,376 @@
+//! Test harness: a broker server on an ephemeral port, talking to a fake
+//! Docker Engine API served over a Unix socket, and the helpers the test
+//! files share.
+
+// Every test file uses a different part of the harness.
+#![allow(dead_code)]
+
+use std::{
+    convert::Infallible,
+    ffi::OsStr,
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tempfile::TempDir;
+use tokio::net::UnixListener;
+
+use quic_server::access::Access;
+use quic_server::client::{Client, Trust};
+use quic_server::config::Config;
+use quic_server::error::{Error, ErrorCode};
+use quic_server::gateway;
+use quic_server::server::{self, Opt};
+use quic_server::state::State;
+use structopt::StructOpt;
+
+/// Name of the only container the fake daemon knows.
+pub const CONTAINER: &str = "web";
+
+/// Its ID, which the daemon accepts in place of the name.
+const CONTAINER_ID: &str = "5e0f5e6cfa2b1e2a4c3e8e2bd6e1b1e3dd3c0b6dbd8ad5a83f1bdfe7d6b5a4c3";
+
+pub struct Harness {
+    pub client: Client,
+    pub dir: TempDir,
+    /// Address of the QUIC endpoint.
+    pub addr: SocketAddr,
+    /// Address of the TLS listener, if started with one.
+    pub tls: Option<SocketAddr>,
+    /// Address of the WebSocket listener, if started with one.
+    pub ws: Option<SocketAddr>,
+    /// Addresses of the `[[listeners]]` in the config, in order.
+    pub listeners: Vec<Option<SocketAddr>>,
+    /// Bodies of the container create requests the fake daemon received.
+    pub created: Created,
+    /// Every request the fake daemon received, as `METHOD path?query` with
+    /// the API version stripped and the query decoded.
+    pub requests: Requests,
+}
+
+impl Harness {
+    /// The server's self-signed certificate, in DER.
+    pub fn cert(&self) -> Vec<u8> {
+        std::fs::read(self.dir.path().join("cert.der")).unwrap()
+    }
+
+    /// A new client connected over QUIC to `addr`, one of the server's
+    /// listeners.
+    pub async fn connect(&self, addr: &SocketAddr) -> Client {
+        Client::connect(addr, "localhost", Trust::Authority(self.cert())).await.unwrap()
+    }
+
+    /// A second client connected to the QUIC endpoint `client` uses.
+    pub async fn reconnect(&self) -> Client {
+        self.connect(&self.addr).await
+    }
+}
+
+/// Code of a broker error.
+pub fn code(err: anyhow::Error) -> ErrorCode {
+    err.downcast::<Error>().unwrap().code
+}
+
+/// Message of a broker error, which must be a `PermissionDenied`.
+pub fn denied(err: anyhow::Error) -> String {
+    let err = err.downcast::<Error>().unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+    err.message
+}
+
+/// Uid the tests run as, which Unix socket clients are known by.
+pub fn uid() -> u32 {
+    std::fs::metadata("/proc/self").unwrap().uid()
+}
+
+pub fn gid() -> u32 {
+    std::fs::metadata("/proc/self").unwrap().gid()
+}
+
+pub type Created = Arc<Mutex<Vec<serde_json::Value>>>;
+
+pub type Requests = Arc<Mutex<Vec<String>>>;
+
+/// Starts a fake Docker daemon and a broker server using it, extra `config`
+/// is appended to the generated config file.
+pub async fn start(config: &str) -> Harness {
+    launch(config, Transport::Quic, &[]).await
+}
+
+/// Like `start`, with extra command line `args` for the server.
+pub async fn start_with(config: &str, args: &[&str]) -> Harness {
+    launch(config, Transport::Quic, args).await
+}
+
+/// Like `start`, but also listens on TLS and connects the client over it.
+pub async fn start_tls(config: &str) -> Harness {
+    launch(config, Transport::Tls, &[]).await
+}
+
+/// Like `start`, but also listens on a Unix socket and connects the client
+/// over it.
+pub async fn start_unix(config: &str) -> Harness {
+    launch(config, Transport::Unix, &[]).await
+}
+
+/// Like `start`, but also listens for WebSocket clients.
+pub async fn start_ws(config: &str) -> Harness {
+    launch(config, Transport::WebSocket, &[]).await
+}
+
+enum Transport {
+    Quic,
+    Tls,
+    Unix,
+    WebSocket,
+}
+
+async fn launch(config: &str, transport: Transport, extra: &[&str]) -> Harness {
+    let dir = tempfile::tempdir().unwrap();
+
+    let socket = dir.path().join("docker.sock");
+    let created = Created::default();
+    let requests = Requests::default();
+    spawn_docker(&socket, created.clone(), requests.clone());
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_der = cert.serialize_der().unwrap();
+    let key_path = dir.path().join("key.der");
+    let cert_path = dir.path().join("cert.der");
+    std::fs::write(&key_path, cert.serialize_private_key_der()).unwrap();
+    std::fs::write(&cert_path, &cert_der).unwrap();
+
+    let config_path = dir.path().join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "[docker]\nsocket = {:?}\n\n[limits.rate]\nrate = 0.0\nburst = 0.0\n\n{}",
+            socket, config
+        ),
+    )
+    .unwrap();
+
+    let mut args = vec![
+        OsStr::new("server"),
+        OsStr::new("--listen"),
+        OsStr::new("127.0.0.1:0"),
+        OsStr::new("--key"),
+        key_path.as_os_str(),
+        OsStr::new("--cert"),
+        cert_path.as_os_str(),
+        OsStr::new("--config"),
+        config_path.as_os_str(),
+    ];
+    let unix_path = dir.path().join("broker.sock");
+    match transport {
+        Transport::Quic => {}
+        Transport::WebSocket => args.extend(vec![OsStr::new("--listen-ws"), OsStr::new("127.0.0.1:0")]),
+        Transport::Tls => args.extend(vec![OsStr::new("--listen-tls"), OsStr::new("127.0.0.1:0")]),
+        Transport::Unix => args.extend(vec![OsStr::new("--listen-unix"), unix_path.as_os_str()]),
+    }
+    args.extend(extra.iter().map(OsStr::new));
+    let opt = Opt::from_iter(args);
+
+    let config = Config::load(&opt.config).await.unwrap();
+    let state = Arc::new(State::new(config).await.unwrap());
+    let access = Access::new(opt.read_only, None).unwrap();
+    let (addr, incoming) = server::bind(&opt, &state, &opt.listen).await.unwrap();
+    let tls = match &opt.listen_tls {
+        Some(listen) => {
+            let (addr, listener, acceptor) = server::tls::bind(&opt, listen).await.unwrap();
+            tokio::spawn(server::tls::serve(state.clone(), listener, acceptor, access.clone()));
+            Some(addr)
+        }
+        None => None,
+    };
+    let ws = match &opt.listen_ws {
+        Some(listen) => {
+            let (addr, listener, acceptor) = gateway::ws::bind(&opt, listen).await.unwrap();
+            tokio::spawn(gateway::ws::serve(state.clone(), listener, acceptor, access.clone()));
+            Some(addr)
+        }
+        None => None,
+    };
+    if let Some(path) = &opt.listen_unix {
+        let listener = server::unix::bind(&state, path).await.unwrap();
+        tokio::spawn(server::unix::serve(state.clone(), listener, access.clone()));
+    }
+    let mut listeners = Vec::new();
+    for listener in &state.config.listeners {
+        listeners.push(server::listen(&opt, &state, listener).await.unwrap());
+    }
+    tokio::spawn(server::serve(state, incoming, access));
+
+    let trust = Trust::Authority(cert_der);
+    let client = match transport {
+        Transport::Quic | Transport::WebSocket => Client::connect(&addr, "localhost", trust).await.unwrap(),
+        Transport::Tls => Client::connect_tls(&tls.unwrap(), "localhost", trust).await.unwrap(),
+        Transport::Unix => Client::connect_unix(&unix_path).await.unwrap(),
+    };
+
+    Harness {
+        client,
+        dir,
+        addr,
+        tls,
+        ws,
+        listeners,
+        created,
+        requests,
+    }
+}
+
+fn spawn_docker(socket: &Path, created: Created, requests: Requests) {
+    let mut listener = UnixListener::bind(socket).unwrap();
+
+    tokio::spawn(async move {
+        let service = make_service_fn(move |_| {
+            let (created, requests) = (created.clone(), requests.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| docker(req, created.clone(), requests.clone())))
+            }
+        });
+        hyper::Server::builder(accept::from_stream(listener.incoming()))
+            .serve(service)
+            .await
+            .unwrap();
+    });
+}
+
+async fn docker(req: Request<Body>, created: Created, requests: Requests) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let query = decode(parts.uri.query().unwrap_or(""));
+
+    // bollard prefixes every path with the API version, e.g. /v1.40.
+    let path = parts.uri.path().trim_start_matches('/');
+    let path = match path.find('/') {
+        Some(i) if path.starts_with('v') => &path[i + 1..],
+        _ => path,
+    };
+    let segments = path.split('/').collect::<Vec<_>>();
+    requests.lock().unwrap().push(format!("{} {}?{}", parts.method, path, query));
+
+    let resp = match (&parts.method, segments.as_slice()) {
+        (&Method::GET, ["_ping"]) => reply(StatusCode::OK, "OK"),
+        (&Method::GET, ["version"]) => json(include_str!("../fixtures/version.json")),
+        (&Method::GET, ["info"]) => json(include_str!("../fixtures/info.json")),
+        // Events end here instead of waiting for more, as if `until` passed.
+        (&Method::GET, ["events"]) => json(include_str!("../fixtures/events.json")),
+        (&Method::GET, ["system", "df"]) => json(include_str!("../fixtures/df.json")),
+        // The only container belongs to tenant blue.
+        (&Method::GET, ["containers", "json"])
+            if query.contains("broker.tenant=") && !query.contains("broker.tenant=blue") =>
+        {
+            json("[]")
+        }
+        // Listing by state finds the stopped container a prune would remove.
+        (&Method::GET, ["containers", "json"]) if query.contains("\"status\"") => {
+            json(include_str!("../fixtures/stopped.json"))
+        }
+        (&Method::GET, ["containers", "json"]) => json(include_str!("../fixtures/list.json")),
+        (&Method::POST, ["containers", "create"]) => {
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            created.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+            reply(
+                StatusCode::CREATED,
+                r#"{"Id": "0b4a5ce2c5f8d3e6a2c1b9f7e4d3c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5", "Warnings": []}"#,
+            )
+        }
+        (&Method::POST, ["images", "create"]) => json(r#"{"status": "Status: Image is up to date", "id": "nginx"}"#),
+        (&Method::POST, ["containers", "prune"]) => json(
+            r#"{"ContainersDeleted": ["3f2a1b0c9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a"], "SpaceReclaimed": 1093}"#,
+        ),
+        (_, ["containers", name, ..]) if *name != CONTAINER && *name != CONTAINER_ID => reply(
+            StatusCode::NOT_FOUND,
+            &format!(r#"{{"message": "No such container: {}"}}"#, name),
+        ),
+        (&Method::GET, ["containers", _, "json"]) => json(include_str!("../fixtures/inspect.json")),
+        (&Method::GET, ["containers", _, "changes"]) => json(include_str!("../fixtures/changes.json")),
+        (&Method::GET, ["containers", _, "stats"]) => json(include_str!("../fixtures/stats.json")),
+        (&Method::GET, ["containers", _, "top"]) => json(include_str!("../fixtures/top.json")),
+        // Followed logs with `tail=hold` stay open, to keep a stream busy.
+        (&Method::GET, ["containers", _, "logs"]) if query.contains("tail=hold") => {
+            let (mut tx, body) = Body::channel();
+            tokio::spawn(async move {
+                let _ = tx.send_data(frames(&["ready\n"]).into()).await;
+                tokio::time::delay_for(std::time::Duration::from_secs(3600)).await;
+            });
+            Response::builder()
+                .header("Content-Type", "application/vnd.docker.raw-stream")
+                .body(body)
+                .unwrap()
+        }
+        (&Method::GET, ["containers", _, "logs"]) => logs(&["listening on port 80\n", "ready\n"]),
+        (&Method::POST, ["containers", _, "update"]) => json(r#"{"Warnings": []}"#),
+        (&Method::POST, ["containers", _, action])
+            if ["start", "stop", "kill", "restart"].contains(action) =>
+        {
+            reply(StatusCode::NO_CONTENT, "")
+        }
+        (&Method::DELETE, ["containers", _]) => reply(StatusCode::NO_CONTENT, ""),
+        _ => reply(StatusCode::NOT_FOUND, r#"{"message": "page not found"}"#),
+    };
+
+    Ok(resp)
+}
+
+/// Percent-decoded query string.
+fn decode(query: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = query.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            }
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).unwrap()
+}
+
+fn json(body: &str) -> Response<Body> {
+    reply(StatusCode::OK, body)
+}
+
+fn reply(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_owned()))
+        .unwrap()
+}
+
+/// Log lines in Docker's multiplexed stdout stream format.
+fn logs(lines: &[&str]) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/vnd.docker.raw-stream")
+        .body(Body::from(frames(lines)))
+        .unwrap()
+}
+
+fn frames(lines: &[&str]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for line in lines {
+        body.extend_from_slice(&[1, 0, 0, 0]);
+        body.extend_from_slice(&(line.len() as u32).to_be_bytes());
+        body.extend_from_slice(line.as_bytes());
+    }
+
+    body
+}
@@ -0,0 +1,185 @@
+//! Tenant namespaces, with clients on the Unix socket belonging to a tenant
+//! by their uid. The fake daemon's only container belongs to tenant blue.
+
+mod support;
+
+use bollard::container::*;
+use broker_proto::{Body, CommandType};
+use quic_server::config::TenantConfig;
+use quic_server::error::ErrorCode;
+use quic_server::tenant::Tenants;
+
+use support::{code, denied, gid, uid, Harness, CONTAINER};
+
+/// Tenants require authentication. Unix socket clients are known by their
+/// uid all the same, so the key file need not exist.
+const AUTH: &str = "[auth]\nkeys_file = \"/nonexistent/keys.toml\"\n";
+
+/// Starts a broker whose Unix socket clients belong to `tenant`.
+async fn start(tenant: &str, quotas: &str) -> Harness {
+    support::start_unix(&format!("{}\n[[tenants]]\nname = \"{}\"\nuids = [{}]\n{}", AUTH, tenant, uid(), quotas)).await
+}
+
+fn image() -> Config<String> {
+    Config {
+        image: Some("nginx".to_owned()),
+        ..Default::default()
+    }
+}
+
+fn limited(memory: u64) -> Config<String> {
+    Config {
+        host_config: Some(HostConfig {
+            memory: Some(memory as _),
+            ..Default::default()
+        }),
+        ..image()
+    }
+}
+
+#[tokio::test]
+async fn list_only_own() {
+    let h = start("blue", "").await;
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    let h = start("red", "").await;
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert!(containers.is_empty()),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn other_tenants_containers_not_found() {
+    let h = start("red", "").await;
+
+    let err = h.client.stop(CONTAINER, None).await.unwrap_err();
+    assert_eq!(err.to_string(), "No such container: web");
+    assert_eq!(code(err), ErrorCode::NotFound);
+
+    let h = start("blue", "").await;
+    h.client.stop(CONTAINER, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn create_stamps_tenant() {
+    let h = start("blue", "").await;
+
+    // Clients cannot move containers into another tenant.
+    let config = Config {
+        labels: Some(vec![("broker.tenant".to_owned(), "red".to_owned())].into_iter().collect()),
+        ..image()
+    };
+    h.client.create(config, None).await.unwrap();
+
+    let created = h.created.lock().unwrap();
+    assert_eq!(created[0]["Labels"]["broker.tenant"], "blue");
+}
+
+#[tokio::test]
+async fn container_quota() {
+    let h = start("blue", "max_containers = 1\n").await;
+
+    let err = h.client.create(image(), None).await.unwrap_err();
+    assert_eq!(denied(err), "Tenant blue is at its quota of 1 containers.");
+    assert!(h.created.lock().unwrap().is_empty());
+
+    let h = start("blue", "max_containers = 2\n").await;
+    h.client.create(image(), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn memory_quota() {
+    let h = start("blue", "max_memory = 1000\n").await;
+
+    let err = h.client.create(image(), None).await.unwrap_err();
+    assert_eq!(denied(err), "Containers of tenant blue need a memory limit.");
+
+    let err = h.client.create(limited(2000), None).await.unwrap_err();
+    assert_eq!(denied(err), "Tenant blue has 1000 of its 1000 bytes of memory left.");
+
+    h.client.create(limited(500), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn memory_quota_on_update() {
+    let h = start("blue", "max_memory = 1000\n").await;
+
+    let options = UpdateContainerOptions {
+        memory: Some(2000),
+        ..Default::default()
+    };
+    let err = h.client.update(CONTAINER, options).await.unwrap_err();
+    assert_eq!(denied(err), "Tenant blue has 1000 of its 1000 bytes of memory left.");
+
+    let options = UpdateContainerOptions {
+        memory: Some(1000),
+        ..Default::default()
+    };
+    h.client.update(CONTAINER, options).await.unwrap();
+}
+
+#[tokio::test]
+async fn host_wide_commands_refused() {
+    let h = start("blue", "").await;
+
+    let err = h.client.pull("nginx", None, None).await.unwrap_err();
+    assert!(denied(err).starts_with("Tenant blue may not use Pull"));
+    let err = h.client.push("nginx", None, None).await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+    let err = h.client.system().await.unwrap_err();
+    assert_eq!(code(err), ErrorCode::PermissionDenied);
+    assert!(h.requests.lock().unwrap().iter().all(|r| !r.contains("images") && !r.contains("info")));
+
+    match h.client.hello().await.unwrap() {
+        Body::Hello { commands, .. } => {
+            assert!(commands.iter().any(|cmd| matches!(cmd, CommandType::List)));
+            assert!(!commands.iter().any(|cmd| matches!(cmd, CommandType::Pull | CommandType::System)));
+        }
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn outsiders_refused() {
+    let h = support::start_unix(&format!("{}\n[[tenants]]\nname = \"blue\"\nuids = [{}]\n", AUTH, uid() + 1)).await;
+
+    let err = h.client.list().await.unwrap_err();
+    assert_eq!(denied(err), format!("Client local uid={} gid={} is in no tenant.", uid(), gid()));
+
+    // Hello still tells them what the listener serves.
+    h.client.hello().await.unwrap();
+}
+
+#[tokio::test]
+async fn operators_see_everything() {
+    let h = support::start_unix(&format!(
+        "{}\n[operators]\nuids = [{}]\n\n[[tenants]]\nname = \"red\"\nuids = [{}]\n",
+        AUTH,
+        uid(),
+        uid() + 1
+    ))
+    .await;
+
+    match h.client.list().await.unwrap() {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+    h.client.stop(CONTAINER, None).await.unwrap();
+}
+
+#[test]
+fn tenants_need_authentication() {
+    let blue = TenantConfig {
+        name: "blue".to_owned(),
+        ..Default::default()
+    };
+    let err = Tenants::new(&[blue.clone()], &Default::default(), false).err().unwrap();
+    assert_eq!(err.to_string(), "Tenants need authentication, configure auth.keys_file or auth.token_key_file.");
+
+    Tenants::new(&[blue], &Default::default(), true).unwrap();
+    Tenants::new(&[], &Default::default(), false).unwrap();
+}
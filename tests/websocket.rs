@@ -0,0 +1,142 @@
+//! Requests over the WebSocket listener, as a browser dashboard sends them.
+
+mod support;
+
+use std::sync::Arc;
+
+use bollard::container::*;
+use broker_proto::{Arguments, Body, CommandType, Protocol};
+use futures::{SinkExt, StreamExt};
+use hyper::Request;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use quic_server::codec::Format;
+use quic_server::error::ErrorCode;
+
+use support::{Harness, CONTAINER};
+
+/// Hex SHA-256 of `secret`.
+const TOKENS: &str = "[[gateway.tokens]]
+name = \"dashboard\"
+sha256 = \"2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\"
+";
+
+type Socket = WebSocketStream<TlsStream<TcpStream>>;
+
+async fn open(h: &Harness, uri: &str, token: Option<&str>) -> Result<Socket, tokio_tungstenite::tungstenite::Error> {
+    let mut config = rustls::ClientConfig::new();
+    config.root_store.add(&rustls::Certificate(h.cert())).unwrap();
+
+    let tcp = TcpStream::connect(h.ws.unwrap()).await.unwrap();
+    let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await.unwrap();
+
+    let mut request = Request::builder().uri(uri);
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+
+    let (ws, _) = tokio_tungstenite::client_async(request.body(()).unwrap(), tls).await?;
+    Ok(ws)
+}
+
+async fn send(ws: &mut Socket, cmd_type: CommandType, argument: Option<Arguments>) {
+    let header = Format::Msgpack.encode(&Protocol::command(cmd_type, argument)).unwrap();
+    ws.send(Message::Binary(header)).await.unwrap();
+}
+
+async fn next(ws: &mut Socket) -> Protocol {
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(frame) => rmp_serde::from_slice(&frame).unwrap(),
+        message => panic!("Unexpected message {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn list() {
+    let h = support::start_ws(TOKENS).await;
+    let mut ws = open(&h, "wss://localhost/", Some("secret")).await.unwrap();
+
+    send(&mut ws, CommandType::List, None).await;
+    match next(&mut ws).await.body {
+        Body::ContainerList(containers) => assert_eq!(containers.len(), 1),
+        body => panic!("Unexpected body {:?}", body),
+    }
+
+    // The socket takes the next request once the first one finished.
+    send(&mut ws, CommandType::Change, Some(Arguments::ContainerChanges { name: CONTAINER.into() })).await;
+    match next(&mut ws).await.body {
+        Body::Change(changes) => assert_eq!(changes.map_or(0, |c| c.len()), 3),
+        body => panic!("Unexpected body {:?}", body),
+    }
+}
+
+#[tokio::test]
+async fn token_in_query() {
+    let h = support::start_ws(TOKENS).await;
+    let mut ws = open(&h, "wss://localhost/?access_token=secret", None).await.unwrap();
+
+    send(&mut ws, CommandType::List, None).await;
+    assert!(next(&mut ws).await.error.is_none());
+}
+
+#[tokio::test]
+async fn unauthorized() {
+    let h = support::start_ws(TOKENS).await;
+
+    assert!(open(&h, "wss://localhost/", None).await.is_err());
+    assert!(open(&h, "wss://localhost/", Some("wrong")).await.is_err());
+}
+
+#[tokio::test]
+async fn followed_logs() {
+    let h = support::start_ws(TOKENS).await;
+    let mut ws = open(&h, "wss://localhost/", Some("secret")).await.unwrap();
+
+    let options = LogsOptions {
+        follow: true,
+        stdout: true,
+        ..Default::default()
+    };
+    send(&mut ws, CommandType::Log, Some(Arguments::Logs { name: CONTAINER.into(), options: Some(options) })).await;
+
+    let mut text = String::new();
+    loop {
+        match next(&mut ws).await.body {
+            Body::LogOutput(lines) if lines.is_empty() => break,
+            Body::LogOutput(lines) => text.extend(lines.iter().map(|l| l.to_string())),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(text, "listening on port 80\nready\n");
+}
+
+#[tokio::test]
+async fn events() {
+    let h = support::start_ws(TOKENS).await;
+    let mut ws = open(&h, "wss://localhost/", Some("secret")).await.unwrap();
+
+    send(&mut ws, CommandType::Events, Some(Arguments::Events { options: None })).await;
+
+    let mut actions = Vec::new();
+    loop {
+        match next(&mut ws).await.body {
+            Body::Events(events) if events.is_empty() => break,
+            Body::Events(events) => actions.extend(events.into_iter().map(|e| e.action)),
+            body => panic!("Unexpected body {:?}", body),
+        }
+    }
+    assert_eq!(actions, vec!["start", "die"]);
+}
+
+#[tokio::test]
+async fn text_messages_rejected() {
+    let h = support::start_ws(TOKENS).await;
+    let mut ws = open(&h, "wss://localhost/", Some("secret")).await.unwrap();
+
+    ws.send(Message::Text("{}".into())).await.unwrap();
+    assert_eq!(next(&mut ws).await.code, Some(ErrorCode::InvalidArgument));
+}